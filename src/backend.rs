@@ -0,0 +1,369 @@
+use std::{io::SeekFrom, path::{Path, PathBuf}};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt, future, stream::{self, BoxStream}};
+use sha2::{Digest, Sha256};
+use tokio::{fs::{self, File}, io::{AsyncReadExt, AsyncSeekExt}};
+use tokio_util::io::ReaderStream;
+use anyhow::Result;
+
+use crate::{db::{self, UPLOADS_TABLE}, engine::ProgressTracker, local::LocalBackend, profiles::ServerProfile, s3::S3Backend, sftp::SftpBackend, webdav::WebDavBackend};
+
+// Checksums of every backend look like "SHA256:<lowercase hex>", the same as local hashes
+pub const HASH_ALGORITHM: &str = "SHA256";
+// Backends that write files in place upload into a hidden temporary file next to them
+const UPLOAD_SUFFIX: &str = ".filesync-upload";
+
+// Remote paths start with '/' at the root of the backend and have no trailing '/'
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    pub etag: Option<String>
+}
+
+#[derive(Debug, Clone)]
+pub enum RemoteEntry {
+    File(RemoteFile),
+    Directory
+}
+
+pub struct RemoteRead {
+    // Zero when the backend couldn't resume and sends the whole file
+    pub offset: u64,
+    pub length: Option<u64>,
+    pub stream: BoxStream<'static, Result<Bytes>>
+}
+
+enum UploadContent {
+    File(String),
+    Bytes(Bytes)
+}
+
+pub struct UploadSource {
+    content: UploadContent,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    pub checksum: Option<String>,
+    tracker: Option<ProgressTracker>
+}
+
+impl UploadSource {
+    pub async fn from_file(local_path: &str, checksum: Option<String>) -> Result<Self> {
+        let metadata = fs::metadata(local_path).await?;
+
+        Ok(UploadSource {
+            content: UploadContent::File(local_path.to_owned()),
+            size: metadata.len(),
+            modified: metadata.modified()?.into(),
+            checksum: checksum,
+            tracker: None
+        })
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        UploadSource {
+            size: data.len() as u64,
+            content: UploadContent::Bytes(Bytes::from(data)),
+            modified: Utc::now(),
+            checksum: None,
+            tracker: None
+        }
+    }
+
+    pub(crate) fn with_tracker(self: Self, tracker: &ProgressTracker) -> Self {
+        UploadSource { tracker: Some(tracker.clone()), ..self }
+    }
+
+    // Backends keep the bookkeeping of resumable uploads under the local path
+    pub fn local_path(self: &Self) -> Option<&str> {
+        match &self.content {
+            UploadContent::File(local_path) => Some(local_path),
+            UploadContent::Bytes(_) => None
+        }
+    }
+
+    // Every attempt opens the content again, the progress starts counting from the offset
+    pub async fn open(self: &Self, offset: u64, length: u64) -> Result<BoxStream<'static, std::io::Result<Bytes>>> {
+        if let Some(tracker) = &self.tracker {
+            tracker.rewind(offset);
+        }
+
+        let stream = match &self.content {
+            UploadContent::File(local_path) => {
+                let mut file = File::open(local_path).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                ReaderStream::new(file.take(length)).boxed()
+            }
+            UploadContent::Bytes(data) => {
+                let start = (offset as usize).min(data.len());
+                let end = start.saturating_add(length as usize).min(data.len());
+                stream::once(future::ready(Ok(data.slice(start..end)))).boxed()
+            }
+        };
+
+        Ok(track_upload(stream, self.tracker.clone()).boxed())
+    }
+}
+
+// Backends are picked at the top of a synchronization and used through generics, so the futures don't need Send bounds here
+#[allow(async_fn_in_trait)]
+pub trait RemoteBackend {
+    async fn check_connection(self: &Self) -> bool;
+
+    // Everything below the directory, on all levels when recursive. None if the directory doesn't exist
+    async fn list(self: &Self, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>>;
+
+    async fn stat(self: &Self, path: &str) -> Result<Option<RemoteEntry>>;
+
+    // False if listed files carry another modification time than stat gives, then times are compared only after stat
+    fn lists_modified_times(self: &Self) -> bool {
+        true
+    }
+
+    // Starts from the offset only if the file still has this ETag. The caller retries reads together with the transfer
+    async fn read(self: &Self, path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead>;
+
+    // Replaces the file, creating missing parent directories. Backends that store modification times take it from the source
+    async fn write(self: &Self, path: &str, source: &UploadSource) -> Result<()>;
+
+    // Creates missing parent directories as well
+    async fn mkdir(self: &Self, dir_path: &str) -> Result<()>;
+
+    // A path that is already missing is not an error
+    async fn delete(self: &Self, path: &str) -> Result<()>;
+
+    async fn rename(self: &Self, from_path: &str, to_path: &str) -> Result<()>;
+
+    // False if the backend has no modification times of its own, then they are kept only in .syncmetadata
+    async fn set_modified(self: &Self, path: &str, modified: DateTime<Utc>) -> Result<bool>;
+
+    // Content hash known to the server, without downloading the file
+    async fn checksum(self: &Self, _path: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    // Changes whenever anything at or below the path changes. Empty for a missing path, None if the backend can't tell.
+    // The tag of the last run may be returned again when the backend can check that nothing changed since it
+    async fn tag(self: &Self, path: &str, known_tag: Option<&str>) -> Result<Option<String>>;
+}
+
+pub enum ServerBackend {
+    Local(LocalBackend),
+    Sftp(SftpBackend),
+    S3(S3Backend),
+    WebDav(WebDavBackend)
+}
+
+// file://, sftp:// and s3:// addresses pick their backends, anything else is WebDAV.
+// For SFTP an empty private key means password authentication, for S3 the login and the password are the access key and the secret key
+pub fn connect(profile: &ServerProfile, max_attempts: u32) -> Result<ServerBackend> {
+    if let Some(backend) = LocalBackend::from_host(&profile.host) {
+        return Ok(ServerBackend::Local(backend));
+    }
+
+    if let Some(backend) = SftpBackend::from_host(&profile.host, &profile.login, &profile.password, &profile.private_key, &profile.host_key) {
+        return Ok(ServerBackend::Sftp(backend));
+    }
+
+    if let Some(backend) = S3Backend::from_host(&profile.host, &profile.login, &profile.password, max_attempts) {
+        return Ok(ServerBackend::S3(backend?));
+    }
+
+    Ok(ServerBackend::WebDav(WebDavBackend::new(profile.host.clone(), profile.login.clone(), profile.password.clone(), max_attempts)?))
+}
+
+macro_rules! dispatch {
+    ($backend:expr, $inner:ident => $call:expr) => {
+        match $backend {
+            ServerBackend::Local($inner) => { $call }
+            ServerBackend::Sftp($inner) => { $call }
+            ServerBackend::S3($inner) => { $call }
+            ServerBackend::WebDav($inner) => { $call }
+        }
+    };
+}
+
+impl RemoteBackend for ServerBackend {
+    async fn check_connection(self: &Self) -> bool {
+        dispatch!(self, backend => backend.check_connection().await)
+    }
+
+    async fn list(self: &Self, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+        dispatch!(self, backend => backend.list(dir_path, recursive).await)
+    }
+
+    async fn stat(self: &Self, path: &str) -> Result<Option<RemoteEntry>> {
+        dispatch!(self, backend => backend.stat(path).await)
+    }
+
+    fn lists_modified_times(self: &Self) -> bool {
+        dispatch!(self, backend => backend.lists_modified_times())
+    }
+
+    async fn read(self: &Self, path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead> {
+        dispatch!(self, backend => backend.read(path, offset, etag).await)
+    }
+
+    async fn write(self: &Self, path: &str, source: &UploadSource) -> Result<()> {
+        dispatch!(self, backend => backend.write(path, source).await)
+    }
+
+    async fn mkdir(self: &Self, dir_path: &str) -> Result<()> {
+        dispatch!(self, backend => backend.mkdir(dir_path).await)
+    }
+
+    async fn delete(self: &Self, path: &str) -> Result<()> {
+        dispatch!(self, backend => backend.delete(path).await)
+    }
+
+    async fn rename(self: &Self, from_path: &str, to_path: &str) -> Result<()> {
+        dispatch!(self, backend => backend.rename(from_path, to_path).await)
+    }
+
+    async fn set_modified(self: &Self, path: &str, modified: DateTime<Utc>) -> Result<bool> {
+        dispatch!(self, backend => backend.set_modified(path, modified).await)
+    }
+
+    async fn checksum(self: &Self, path: &str) -> Result<Option<String>> {
+        dispatch!(self, backend => backend.checksum(path).await)
+    }
+
+    async fn tag(self: &Self, path: &str, known_tag: Option<&str>) -> Result<Option<String>> {
+        dispatch!(self, backend => backend.tag(path, known_tag).await)
+    }
+}
+
+// State of an upload that is continued after a restart, kept by the local path
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub(crate) struct PartialUpload<T> {
+    pub remote_path: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    // What the backend needs to continue the transfer
+    pub transfer: T
+}
+
+impl<T> PartialUpload<T> {
+    pub fn new(remote_path: &str, source: &UploadSource, transfer: T) -> Self {
+        PartialUpload {
+            remote_path: remote_path.to_owned(),
+            size: source.size,
+            modified: source.modified,
+            transfer: transfer
+        }
+    }
+
+    // Only the same version of the file continues the upload
+    pub fn is_for(self: &Self, remote_path: &str, source: &UploadSource) -> bool {
+        self.remote_path == remote_path && self.size == source.size && self.modified == source.modified
+    }
+}
+
+pub(crate) async fn load_partial_upload<T: serde::de::DeserializeOwned>(local_path: &str) -> Result<Option<PartialUpload<T>>> {
+    let local_path = local_path.to_owned();
+    match db::blocking(move || db::read_bytes(UPLOADS_TABLE, &local_path)).await? {
+        Some(data) => Ok(postcard::from_bytes::<PartialUpload<T>>(&data).ok()),
+        None => Ok(None)
+    }
+}
+
+pub(crate) async fn save_partial_upload<T: serde::Serialize>(local_path: &str, partial_upload: &PartialUpload<T>) -> Result<()> {
+    let local_path = local_path.to_owned();
+    let data = postcard::to_allocvec(partial_upload)?;
+    db::blocking(move || db::write_bytes(UPLOADS_TABLE, &local_path, &data)).await
+}
+
+pub(crate) async fn clear_partial_upload(local_path: &str) -> Result<()> {
+    let local_path = local_path.to_owned();
+    db::blocking(move || db::delete_bytes(UPLOADS_TABLE, &local_path)).await
+}
+
+pub(crate) fn upload_temp_path(full_path: &Path) -> PathBuf {
+    let file_name = full_path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    full_path.with_file_name(format!(".{}{}", file_name, UPLOAD_SUFFIX))
+}
+
+// Lists the directory through read_dir, which gives the names and entries inside one directory or None if it is missing
+pub(crate) fn walk_directory<F>(dir_path: &str, recursive: bool, mut read_dir: F) -> Result<Option<Vec<(String, RemoteEntry)>>>
+where
+    F: FnMut(&str) -> Result<Option<Vec<(String, RemoteEntry)>>>
+{
+    let top_dir = format!("/{}", dir_path.trim_matches('/'));
+    let mut entries = Vec::new();
+    let mut pending_dirs = vec![top_dir.clone()];
+
+    while let Some(dir_path) = pending_dirs.pop() {
+        let listing = match read_dir(&dir_path)? {
+            Some(listing) => { listing }
+            None if dir_path == top_dir => { return Ok(None) }
+            // Removed while the tree was walked
+            None => { continue; }
+        };
+
+        for (name, entry) in listing {
+            // Left by an interrupted upload, the next upload of the file starts it again
+            if name.ends_with(UPLOAD_SUFFIX) {
+                continue;
+            }
+
+            let path = format!("{}/{}", dir_path.trim_end_matches('/'), name);
+            if recursive && matches!(entry, RemoteEntry::Directory) {
+                pending_dirs.push(path.clone());
+            }
+            entries.push((path, entry));
+        }
+    }
+
+    Ok(Some(entries))
+}
+
+pub(crate) struct TreeNode {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: u128,
+    pub is_dir: bool
+}
+
+// Sizes and modification times of everything at or below the roots, read_dir gives the nodes inside one directory
+pub(crate) fn tree_fingerprint<F>(roots: Vec<TreeNode>, mut read_dir: F) -> Result<String>
+where
+    F: FnMut(&Path) -> Result<Vec<TreeNode>>
+{
+    let mut hasher = Sha256::new();
+    let mut pending_nodes = roots;
+
+    while let Some(node) = pending_nodes.pop() {
+        hasher.update(node.path.as_os_str().as_encoded_bytes());
+        hasher.update(node.size.to_le_bytes());
+        hasher.update(node.modified.to_le_bytes());
+
+        if node.is_dir {
+            let mut children = read_dir(&node.path)?;
+
+            // Reversed, so the stack visits children in a stable order
+            children.sort_by(|a, b| b.path.cmp(&a.path));
+            pending_nodes.extend(children);
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn track_upload<S>(stream: S, tracker: Option<ProgressTracker>) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = std::io::Result<Bytes>>
+{
+    stream.then(move |chunk| {
+        let tracker = tracker.clone();
+        async move {
+            if let (Some(tracker), Ok(chunk)) = (&tracker, &chunk) {
+                let bytes = chunk.len() as u64;
+                tracker.advance(bytes);
+                tracker.pace(bytes).await;
+            }
+            chunk
+        }
+    })
+}
@@ -0,0 +1,407 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::{StreamExt, channel::mpsc};
+use serde_json::json;
+use tokio::runtime::Runtime;
+
+use filesync_rust::{ConflictPolicy, ConflictResolution, ServerProfile, SyncEngine, SyncEvent, SyncPurpose, SyncState, db::{self, PAIRS_TABLE}, engine::{self, PairOptions}, profiles::{self, DEFAULT_PROFILE}};
+
+use crate::daemon::{self, DaemonRequest};
+
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_DIFFERENCES: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+#[derive(Parser)]
+#[command(name = "filesync", about = "Synchronizes local files with a WebDAV server without the GUI")]
+struct Cli {
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Synchronize all pairs
+    Sync,
+    /// Compare all pairs without changing anything
+    Check,
+    /// Manage synchronization pairs
+    Pairs {
+        #[command(subcommand)]
+        command: PairsCommand
+    },
+    /// Manage server profiles
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommand
+    },
+    /// Run or control the background service
+    Daemon {
+        #[command(subcommand)]
+        command: DaemonCommand
+    }
+}
+
+#[derive(Subcommand)]
+enum PairsCommand {
+    /// Add a pair of a local path and a server path
+    Add {
+        local_path: String,
+        remote_path: String,
+        /// Server profile to synchronize with
+        #[arg(long, default_value = DEFAULT_PROFILE)]
+        profile: String,
+        #[arg(long)]
+        max_deletions: Option<u64>,
+        /// Upload limit in KB/s
+        #[arg(long)]
+        upload_limit: Option<u64>,
+        /// Download limit in KB/s
+        #[arg(long)]
+        download_limit: Option<u64>
+    },
+    /// List all pairs
+    List,
+    /// Remove the pair of a local path
+    Remove {
+        local_path: String
+    }
+}
+
+#[derive(Subcommand)]
+enum DaemonCommand {
+    /// Keep watching and synchronizing until stopped
+    Run,
+    /// Show the state of the running daemon
+    Status,
+    /// Ask the daemon to synchronize all pairs
+    Sync,
+    /// Ask the daemon to check all pairs
+    Check,
+    /// Stop automatic synchronization and cancel the running one
+    Pause,
+    /// Continue automatic synchronization
+    Resume,
+    /// Answer a conflict shown by the status
+    Resolve {
+        local_path: String,
+        resolution: Resolution
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Resolution {
+    #[value(name = "keep-local")]
+    Local,
+    #[value(name = "keep-remote")]
+    Remote,
+    #[value(name = "keep-both")]
+    Both
+}
+
+#[derive(Subcommand)]
+enum AuthCommand {
+    /// Save the server address and credentials
+    Set {
+        /// Name of the profile, pairs reference it
+        #[arg(long, default_value = DEFAULT_PROFILE)]
+        profile: String,
+        /// WebDAV URL, sftp://host[:port]/path, s3://host[:port]/bucket[/prefix], or file:///path to synchronize with a local directory
+        host: String,
+        #[arg(default_value = "")]
+        login: String,
+        /// Password, or the passphrase of the private key
+        #[arg(long, env = "FILESYNC_PASSWORD", hide_env_values = true, default_value = "")]
+        password: String,
+        /// Private key for SFTP servers instead of the password
+        #[arg(long, default_value = "")]
+        private_key: String,
+        /// SHA256:... fingerprint of the SFTP server key, trusts a server missing from known_hosts
+        #[arg(long, default_value = "")]
+        host_key: String
+    },
+    /// List server profiles
+    List,
+    /// Remove a profile no pair uses
+    Remove {
+        profile: String
+    }
+}
+
+// Returns the process exit code
+pub fn run() -> i32 {
+    let cli = Cli::parse();
+
+    // The daemon owns the database while it runs, listing pairs is the only thing it answers for the others
+    let needs_database = !matches!(cli.command, Command::Daemon { .. } | Command::Pairs { command: PairsCommand::List });
+    if needs_database && let Err(e) = open_database() {
+        return report_error(&e, cli.json);
+    }
+
+    let result = match cli.command {
+        Command::Sync => { return run_sync(SyncPurpose::Synchronize, cli.json); }
+        Command::Check => { return run_sync(SyncPurpose::Check, cli.json); }
+        Command::Pairs { command: PairsCommand::Add { local_path, remote_path, profile, max_deletions, upload_limit, download_limit } } => {
+            add_pair(&local_path, &remote_path, &profile, PairOptions { max_deletions, upload_limit, download_limit }, cli.json)
+        }
+        Command::Pairs { command: PairsCommand::List } => { list_pairs(cli.json) }
+        Command::Pairs { command: PairsCommand::Remove { local_path } } => { remove_pair(&local_path, cli.json) }
+        Command::Auth { command: AuthCommand::Set { profile, host, login, password, private_key, host_key } } => {
+            set_auth(&profile, ServerProfile { host, login, password, private_key, host_key })
+        }
+        Command::Auth { command: AuthCommand::List } => { list_profiles(cli.json) }
+        Command::Auth { command: AuthCommand::Remove { profile } } => { profiles::delete_profile(&profile).map_err(|e| e.to_string()) }
+        Command::Daemon { command: DaemonCommand::Run } => { daemon::serve().map_err(|e| e.to_string()) }
+        Command::Daemon { command } => {
+            let request = match command {
+                DaemonCommand::Run | DaemonCommand::Status => DaemonRequest::Status,
+                DaemonCommand::Sync => DaemonRequest::Sync,
+                DaemonCommand::Check => DaemonRequest::Check,
+                DaemonCommand::Pause => DaemonRequest::Pause,
+                DaemonCommand::Resume => DaemonRequest::Resume,
+                DaemonCommand::Resolve { local_path, resolution } => DaemonRequest::Resolve {
+                    // The daemon knows conflicts by absolute paths
+                    local_path: crate::normalize_pair(&local_path, "/").map(|(local_path, _)| local_path).unwrap_or(local_path),
+                    resolution: match resolution {
+                        Resolution::Local => ConflictResolution::KeepLocal,
+                        Resolution::Remote => ConflictResolution::KeepRemote,
+                        Resolution::Both => ConflictResolution::KeepBoth
+                    }
+                }
+            };
+            control_daemon(request, cli.json)
+        }
+    };
+
+    match result {
+        Ok(_) => { EXIT_SUCCESS }
+        Err(e) => { report_error(&e, cli.json) }
+    }
+}
+
+fn report_error(e: &str, as_json: bool) -> i32 {
+    if as_json {
+        println!("{}", json!({ "error": e }));
+    } else {
+        eprintln!("error: {}", e);
+    }
+    EXIT_ERROR
+}
+
+fn open_database() -> Result<(), String> {
+    if daemon::is_running() {
+        return Err(String::from("The daemon is running, use `daemon sync`, `daemon check` and `daemon status` or stop it to change pairs and profiles"));
+    }
+
+    db::open().map_err(|e| format!("Can't open the database, the window or another command may be using it: {}", e))
+}
+
+fn run_sync(purpose: SyncPurpose, as_json: bool) -> i32 {
+    let pairs = Arc::new(crate::load_sync_pairs());
+
+    let engine = SyncEngine::new(profiles::load_profiles(), crate::load_sync_settings());
+    let (events, mut receiver) = mpsc::channel(100);
+    // Nobody is there to answer, conflicts stay for the GUI
+    let sync = engine.run(events, pairs.clone(), purpose, ConflictPolicy::Defer);
+
+    let report = async {
+        let mut syncstates = BTreeMap::new();
+        let mut errors = Vec::new();
+
+        while let Some(event) = receiver.next().await {
+            match event {
+                SyncEvent::PairState(key, syncstate) => { syncstates.insert(key, syncstate); }
+                SyncEvent::Error(e) => { errors.push(e); }
+                _ => {}
+            }
+        }
+
+        (syncstates, errors)
+    };
+
+    let rt = match Runtime::new() {
+        Ok(rt) => { rt }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+    let (_, (syncstates, errors)) = rt.block_on(async { tokio::join!(sync, report) });
+
+    let exit_code = if !errors.is_empty() || pairs.iter().any(|pair| matches!(syncstates.get(&pair.local_path), None | Some(SyncState::CantSynchronize | SyncState::Cancelled))) {
+        EXIT_ERROR
+    } else if syncstates.values().any(|syncstate| *syncstate != SyncState::Synchronized) {
+        EXIT_DIFFERENCES
+    } else {
+        EXIT_SUCCESS
+    };
+
+    if as_json {
+        let pairs_json: Vec<_> = pairs
+            .iter()
+            .map(|pair| json!({
+                "local_path": pair.local_path,
+                "remote_path": pair.remote_path,
+                "profile": pair.profile,
+                "state": syncstates.get(&pair.local_path).map(syncstate_name)
+            }))
+            .collect();
+        println!("{}", json!({ "pairs": pairs_json, "errors": errors, "exit_code": exit_code }));
+    } else {
+        for pair in pairs.iter() {
+            println!("{:<22} {} <=> {}", syncstates.get(&pair.local_path).map(syncstate_name).unwrap_or("unknown"), pair.local_path, pair.remote_path);
+        }
+        for e in errors {
+            eprintln!("error: {}", e);
+        }
+    }
+
+    exit_code
+}
+
+fn syncstate_name(syncstate: &SyncState) -> &'static str {
+    match syncstate {
+        SyncState::Synchronized => "synchronized",
+        SyncState::UnsynchronizedRemote => "local_changes",
+        SyncState::UnsynchronizedLocal => "remote_changes",
+        SyncState::Conflict => "conflict",
+        SyncState::CantSynchronize => "cant_synchronize",
+        SyncState::Cancelled => "cancelled"
+    }
+}
+
+fn add_pair(local_path: &str, remote_path: &str, profile: &str, pair_options: PairOptions, as_json: bool) -> Result<(), String> {
+    let (local_path, remote_path) = crate::normalize_pair(local_path, remote_path)?;
+    let pairs = db::read_as_map(PAIRS_TABLE).unwrap_or_default();
+
+    if pairs.contains_key(&local_path) {
+        return Err(String::from("This system path already in use"));
+    }
+
+    // The same path on another server is a different place
+    if pairs.iter().any(|(key, value)| *value == remote_path && profiles::load_pair_profile(key) == profile) {
+        return Err(String::from("This server path already in use"));
+    }
+
+    if profile != DEFAULT_PROFILE && !profiles::load_profiles().contains_key(profile) {
+        return Err(format!("No profile {}", profile));
+    }
+
+    engine::save_pair_options(&local_path, &pair_options).map_err(|e| e.to_string())?;
+    profiles::save_pair_profile(&local_path, profile).map_err(|e| e.to_string())?;
+    db::write(PAIRS_TABLE, &local_path, &remote_path).map_err(|e| e.to_string())?;
+
+    if as_json {
+        println!("{}", json!({ "local_path": local_path, "remote_path": remote_path, "profile": profile }));
+    } else {
+        println!("{} <=> {}: {}", local_path, profile, remote_path);
+    }
+
+    Ok(())
+}
+
+fn list_pairs(as_json: bool) -> Result<(), String> {
+    let pairs: Vec<(String, String, String)> = match daemon::request(DaemonRequest::Status) {
+        Ok(status) => {
+            status.pairs
+                .into_iter()
+                .map(|pair| (pair.local_path, pair.remote_path, pair.profile))
+                .collect()
+        }
+        Err(_) => {
+            open_database()?;
+            db::read_as_map(PAIRS_TABLE)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(|(key, value)| {
+                    let profile = profiles::load_pair_profile(&key);
+                    (key, value, profile)
+                })
+                .collect()
+        }
+    };
+
+    if as_json {
+        let pairs_json: Vec<_> = pairs
+            .iter()
+            .map(|(key, value, profile)| json!({ "local_path": key, "remote_path": value, "profile": profile }))
+            .collect();
+        println!("{}", json!(pairs_json));
+    } else {
+        for (key, value, profile) in &pairs {
+            println!("{} <=> {}: {}", key, profile, value);
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_pair(local_path: &str, as_json: bool) -> Result<(), String> {
+    let pairs = db::read_as_map(PAIRS_TABLE).unwrap_or_default();
+
+    // The path may be given the same way it was added or as stored
+    let key = match crate::normalize_pair(local_path, "/") {
+        Ok((key, _)) if pairs.contains_key(&key) => { key }
+        _ if pairs.contains_key(local_path) => { local_path.to_owned() }
+        _ => { return Err(format!("No pair for {}", local_path)); }
+    };
+
+    crate::forget_pair_state(&key).map_err(|e| e.to_string())?;
+    db::delete(PAIRS_TABLE, &key).map_err(|e| e.to_string())?;
+
+    if as_json {
+        println!("{}", json!({ "removed": key }));
+    } else {
+        println!("removed: {}", key);
+    }
+
+    Ok(())
+}
+
+fn control_daemon(request: DaemonRequest, as_json: bool) -> Result<(), String> {
+    let status = daemon::request(request).map_err(|e| e.to_string())?;
+
+    if as_json {
+        println!("{}", json!(status));
+        return Ok(());
+    }
+
+    println!("daemon: {}", status.description());
+
+    for pair in &status.pairs {
+        println!("{:<22} {} <=> {}", pair.state.as_ref().map(syncstate_name).unwrap_or("unknown"), pair.local_path, pair.remote_path);
+    }
+
+    for conflict in &status.conflicts {
+        println!("{:<22} {} <=> {}", "conflict", conflict.local_path, conflict.remote_path);
+    }
+
+    Ok(())
+}
+
+fn set_auth(profile_name: &str, profile: ServerProfile) -> Result<(), String> {
+    profiles::save_profile(profile_name, &profile).map_err(|e| e.to_string())
+}
+
+fn list_profiles(as_json: bool) -> Result<(), String> {
+    let profiles = profiles::load_profiles();
+
+    // Passwords are not printed
+    if as_json {
+        let profiles_json: Vec<_> = profiles
+            .iter()
+            .map(|(name, profile)| json!({ "name": name, "host": profile.host, "login": profile.login, "private_key": profile.private_key, "host_key": profile.host_key }))
+            .collect();
+        println!("{}", json!(profiles_json));
+    } else {
+        for (name, profile) in &profiles {
+            println!("{:<16} {} {}", name, profile.host, profile.login);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,442 @@
+use std::{collections::{BTreeSet, HashMap, HashSet, VecDeque}, sync::{Arc, Mutex, MutexGuard, PoisonError}, time::Duration};
+
+use anyhow::{Result, anyhow};
+use iced::{futures::{SinkExt, Stream, StreamExt, channel::mpsc}, stream};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use filesync_rust::{Conflict, ConflictPolicy, ConflictResolution, SyncEngine, SyncEvent, SyncHandle, SyncPair, SyncPurpose, SyncState, TransferProgress, db::{self, PAIRS_TABLE}, profiles};
+
+use crate::{Message, scheduler, watcher};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const KEPT_ERRORS: usize = 50;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum DaemonRequest {
+    Status,
+    Sync,
+    Check,
+    Pause,
+    Resume,
+    // Answers a conflict, the pair is synchronized again to apply it
+    Resolve { local_path: String, resolution: ConflictResolution }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct DaemonStatus {
+    pub paused: bool,
+    pub running: Option<SyncPurpose>,
+    pub pairs: Vec<PairStatus>,
+    pub progress: Option<TransferProgress>,
+    // Recent errors with increasing numbers, so clients can tell the new ones
+    pub errors: Vec<(u64, String)>,
+    // Conflicts waiting for an answer
+    pub conflicts: Vec<Conflict>
+}
+
+impl DaemonStatus {
+    pub fn description(self: &Self) -> &'static str {
+        match (&self.running, self.paused) {
+            (_, true) => "paused",
+            (Some(SyncPurpose::Synchronize), _) => "synchronizing",
+            (Some(SyncPurpose::Check), _) => "checking",
+            (None, _) => "idle"
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PairStatus {
+    pub local_path: String,
+    pub remote_path: String,
+    pub profile: String,
+    pub state: Option<SyncState>
+}
+
+#[derive(Clone)]
+struct Trigger {
+    purpose: SyncPurpose,
+    scope: Option<BTreeSet<String>>
+}
+
+impl Trigger {
+    fn merge(self, other: Trigger) -> Trigger {
+        Trigger {
+            purpose: match (self.purpose, other.purpose) {
+                (SyncPurpose::Check, SyncPurpose::Check) => SyncPurpose::Check,
+                _ => SyncPurpose::Synchronize
+            },
+            scope: match (self.scope, other.scope) {
+                (Some(mut scope), Some(other_scope)) => {
+                    scope.extend(other_scope);
+                    Some(scope)
+                }
+                _ => None
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct DaemonState {
+    paused: bool,
+    running: Option<SyncPurpose>,
+    syncstates: HashMap<String, SyncState>,
+    progress: Option<TransferProgress>,
+    errors: VecDeque<(u64, String)>,
+    errors_count: u64,
+    sync_handle: Option<SyncHandle>,
+    conflicts: Vec<Conflict>,
+    // Conflicts reported by the running synchronization
+    reported_conflicts: HashSet<String>,
+    answers: Vec<(Conflict, ConflictResolution)>,
+    // Requested or cancelled while paused, runs on resume
+    pending_trigger: Option<Trigger>
+}
+
+impl DaemonState {
+    fn push_error(self: &mut Self, error: String) {
+        self.errors_count += 1;
+        self.errors.push_back((self.errors_count, error));
+
+        if self.errors.len() > KEPT_ERRORS {
+            self.errors.pop_front();
+        }
+    }
+
+    fn defer(self: &mut Self, trigger: Trigger) {
+        self.pending_trigger = Some(match self.pending_trigger.take() {
+            Some(pending_trigger) => pending_trigger.merge(trigger),
+            None => trigger
+        });
+    }
+}
+
+struct Daemon {
+    state: Mutex<DaemonState>,
+    triggers: UnboundedSender<Trigger>
+}
+
+impl Daemon {
+    fn state(self: &Self) -> MutexGuard<'_, DaemonState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn trigger(self: &Self, purpose: SyncPurpose, scope: Option<BTreeSet<String>>) {
+        let _ = self.triggers.send(Trigger { purpose, scope });
+    }
+
+    fn status(self: &Self) -> DaemonStatus {
+        let pairs = db::read_as_map(PAIRS_TABLE).unwrap_or_default();
+
+        let state = self.state();
+        DaemonStatus {
+            paused: state.paused,
+            running: state.running.clone(),
+            pairs: pairs
+                .into_iter()
+                .map(|(local_path, remote_path)| PairStatus {
+                    state: state.syncstates.get(&local_path).cloned(),
+                    profile: profiles::load_pair_profile(&local_path),
+                    local_path: local_path,
+                    remote_path: remote_path
+                })
+                .collect(),
+            progress: state.progress.clone(),
+            errors: state.errors.iter().cloned().collect(),
+            conflicts: state.conflicts.clone()
+        }
+    }
+
+    fn handle_request(self: &Self, request: DaemonRequest) -> Result<DaemonStatus, String> {
+        match request {
+            DaemonRequest::Status => {}
+            DaemonRequest::Sync | DaemonRequest::Check if self.state().paused => {
+                return Err(String::from("Synchronization is paused"));
+            }
+            DaemonRequest::Sync => { self.trigger(SyncPurpose::Synchronize, None); }
+            DaemonRequest::Check => { self.trigger(SyncPurpose::Check, None); }
+            DaemonRequest::Pause => {
+                let mut state = self.state();
+                state.paused = true;
+
+                if let Some(sync_handle) = &state.sync_handle {
+                    sync_handle.cancel.cancel();
+                }
+            }
+            DaemonRequest::Resume => {
+                let pending_trigger = {
+                    let mut state = self.state();
+                    state.paused = false;
+                    state.pending_trigger.take()
+                };
+
+                if let Some(trigger) = pending_trigger {
+                    let _ = self.triggers.send(trigger);
+                }
+            }
+            DaemonRequest::Resolve { resolution: ConflictResolution::DecideLater, .. } => {}
+            DaemonRequest::Resolve { local_path, resolution } => {
+                let pair_key = {
+                    let mut state = self.state();
+                    let Some(index) = state.conflicts.iter().position(|conflict| conflict.local_path == local_path) else {
+                        return Err(format!("No conflict for {}", local_path));
+                    };
+                    let conflict = state.conflicts.remove(index);
+                    let pair_key = conflict.pair_key.clone();
+                    state.answers.push((conflict, resolution));
+                    pair_key
+                };
+
+                // While paused the answer waits for the next synchronization of the pair
+                self.trigger(SyncPurpose::Synchronize, Some(BTreeSet::from([pair_key])));
+            }
+        }
+
+        Ok(self.status())
+    }
+
+    fn apply_event(self: &Self, event: SyncEvent) {
+        let mut state = self.state();
+
+        match event {
+            SyncEvent::Started(sync_handle) => { state.sync_handle = Some(sync_handle); }
+            SyncEvent::PairState(key, syncstate) => {
+                // Conflicts of a synchronized pair that were not reported again are gone
+                if let (Some(SyncPurpose::Synchronize), SyncState::Synchronized | SyncState::Conflict) = (&state.running, &syncstate) {
+                    let DaemonState { conflicts, reported_conflicts, .. } = &mut *state;
+                    conflicts.retain(|conflict| conflict.pair_key != key || reported_conflicts.contains(&conflict.local_path));
+                }
+                state.syncstates.insert(key, syncstate);
+            }
+            SyncEvent::Progress(progress) => { state.progress = Some(progress); }
+            SyncEvent::Error(e) => { state.push_error(e); }
+            SyncEvent::Conflict(conflict) => {
+                // Conflicts wait for an answer from a client
+                state.reported_conflicts.insert(conflict.local_path.clone());
+                state.conflicts.retain(|known| known.local_path != conflict.local_path);
+                state.conflicts.push(conflict);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Status updates for the GUI, sent only while a daemon is running
+pub fn watch_status() -> impl Stream<Item = Message> {
+    stream::channel(100, |mut output: mpsc::Sender<Message>| async move {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut connected = false;
+
+            loop {
+                let status = request(DaemonRequest::Status).ok();
+
+                if status.is_some() || connected {
+                    connected = status.is_some();
+                    let _ = output.send(Message::DaemonStatusChanged(status)).await;
+                }
+
+                tokio::time::sleep(STATUS_POLL_INTERVAL).await;
+            }
+        });
+    })
+}
+
+// Every database has its own daemon
+#[cfg(unix)]
+fn socket_path() -> std::path::PathBuf {
+    db::path().with_extension("sock")
+}
+
+pub fn is_running() -> bool {
+    request(DaemonRequest::Status).is_ok()
+}
+
+#[cfg(unix)]
+pub fn request(request: DaemonRequest) -> Result<DaemonStatus> {
+    use std::{io::{BufRead, BufReader, Write}, os::unix::net::UnixStream};
+
+    let mut stream = UnixStream::connect(socket_path())?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut data = serde_json::to_vec(&request)?;
+    data.push(b'\n');
+    stream.write_all(&data)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    serde_json::from_str::<Result<DaemonStatus, String>>(&line)?.map_err(|e| anyhow!(e))
+}
+
+#[cfg(not(unix))]
+pub fn request(_request: DaemonRequest) -> Result<DaemonStatus> {
+    Err(anyhow!("Daemon mode needs Unix domain sockets"))
+}
+
+#[cfg(not(unix))]
+pub fn serve() -> Result<()> {
+    Err(anyhow!("Daemon mode needs Unix domain sockets"))
+}
+
+#[cfg(unix)]
+pub fn serve() -> Result<()> {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+    use tokio::net::UnixListener;
+
+    if is_running() {
+        return Err(anyhow!("Daemon is already running"));
+    }
+
+    // The daemon is the only owner of the database while it runs, clients go through the socket
+    db::open().map_err(|e| anyhow!("Can't open the database, the window or a command may be using it: {}", e))?;
+
+    // Left by a daemon that didn't stop cleanly
+    let new_socket_path = db::path().with_extension("sock-new");
+    let _ = std::fs::remove_file(socket_path());
+    let _ = std::fs::remove_file(&new_socket_path);
+
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        // Only the owner may control the daemon, so the socket gets its name after its permissions
+        let listener = UnixListener::bind(&new_socket_path)?;
+        std::fs::set_permissions(&new_socket_path, Permissions::from_mode(0o600))?;
+        std::fs::rename(&new_socket_path, socket_path())?;
+        let (triggers, mut triggers_receiver) = unbounded_channel();
+        let daemon = Arc::new(Daemon {
+            state: Mutex::new(DaemonState::default()),
+            triggers: triggers
+        });
+
+        // Like the GUI, the daemon checks everything on start
+        daemon.trigger(SyncPurpose::Check, None);
+
+        let accept = async {
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(handle_connection(daemon.clone(), stream));
+            }
+        };
+
+        tokio::select! {
+            result = accept => result,
+            _ = run_syncs(&daemon, &mut triggers_receiver) => Ok(()),
+            _ = watch_and_schedule(&daemon) => Ok(())
+        }
+    })
+}
+
+#[cfg(unix)]
+async fn handle_connection(daemon: Arc<Daemon>, stream: tokio::net::UnixStream) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<DaemonRequest>(&line) {
+            Ok(request) => { daemon.handle_request(request) }
+            Err(e) => { Err(e.to_string()) }
+        };
+
+        let mut data = serde_json::to_vec(&response)?;
+        data.push(b'\n');
+        writer.write_all(&data).await?;
+    }
+
+    Ok(())
+}
+
+async fn run_syncs(daemon: &Daemon, triggers: &mut UnboundedReceiver<Trigger>) {
+    while let Some(mut trigger) = triggers.recv().await {
+        // Everything requested during the previous run is done in one go
+        while let Ok(next_trigger) = triggers.try_recv() {
+            trigger = trigger.merge(next_trigger);
+        }
+
+        if daemon.state().paused {
+            daemon.state().defer(trigger);
+            continue;
+        }
+
+        let pairs: Vec<SyncPair> = crate::load_sync_pairs()
+            .into_iter()
+            .filter(|pair| trigger.scope.as_ref().is_none_or(|scope| scope.contains(&pair.local_path)))
+            .collect();
+
+        let conflict_policy = {
+            let mut state = daemon.state();
+            state.running = Some(trigger.purpose.clone());
+            state.reported_conflicts.clear();
+
+            // Answers are applied only by a synchronization of their pair
+            match trigger.purpose {
+                SyncPurpose::Synchronize => {
+                    let (answers, kept_answers) = std::mem::take(&mut state.answers)
+                        .into_iter()
+                        .partition(|(conflict, _)| pairs.iter().any(|pair| pair.local_path == conflict.pair_key));
+                    state.answers = kept_answers;
+                    ConflictPolicy::Answered(answers)
+                }
+                SyncPurpose::Check => ConflictPolicy::Defer
+            }
+        };
+
+        let engine = SyncEngine::new(profiles::load_profiles(), crate::load_sync_settings());
+        let (events, mut receiver) = mpsc::channel(100);
+        let sync = engine.run(events, Arc::new(pairs), trigger.purpose.clone(), conflict_policy);
+        let report = async {
+            while let Some(event) = receiver.next().await {
+                daemon.apply_event(event);
+            }
+        };
+        tokio::join!(sync, report);
+
+        let mut state = daemon.state();
+        state.running = None;
+        state.progress = None;
+        state.sync_handle = None;
+
+        // A pause cancels the run, it is repeated on resume
+        if state.paused {
+            state.defer(trigger);
+        }
+    }
+}
+
+async fn watch_and_schedule(daemon: &Daemon) {
+    let pairs: Arc<Vec<SyncPair>> = Arc::new(crate::load_sync_pairs());
+    let schedule = crate::load_schedule();
+    let purpose = schedule.purpose.clone();
+    let engine = SyncEngine::new(profiles::load_profiles(), crate::load_sync_settings());
+
+    let (output, mut messages) = mpsc::channel(100);
+    let watch = {
+        let output = output.clone();
+        let pairs = pairs.clone();
+        async move {
+            if crate::read_setting("watch").unwrap_or(false) {
+                watcher::watch_pairs(output, pairs).await;
+            }
+        }
+    };
+    let scheduled = scheduler::run_schedule(output, engine, pairs, schedule);
+    let events = async {
+        while let Some(message) = messages.next().await {
+            match message {
+                Message::LocalChanges(keys) => { daemon.trigger(SyncPurpose::Synchronize, Some(keys.into_iter().collect())); }
+                Message::ScheduledSync(keys) => { daemon.trigger(purpose.clone(), keys.map(BTreeSet::from_iter)); }
+                Message::ShowError(e) => { daemon.state().push_error(e); }
+                _ => {}
+            }
+        }
+    };
+
+    tokio::join!(watch, scheduled, events);
+    // Nothing to watch and nothing scheduled, the daemon keeps serving requests
+    std::future::pending::<()>().await;
+}
@@ -1,12 +1,65 @@
-use bimap::BiHashMap;
-use redb::{Database, Error, ReadableDatabase, ReadableTable, TableDefinition};
+use std::{collections::{BTreeMap, HashMap}, path::{self, Path, PathBuf}, sync::{Mutex, OnceLock, PoisonError}};
+
+use redb::{Database, Error, ReadableDatabase, ReadableTable, TableDefinition, TableError};
 
 pub const PAIRS_TABLE: TableDefinition<&str, &str> = TableDefinition::new("pairs");
 pub const AUTH_TABLE: TableDefinition<&str, &str> = TableDefinition::new("auth");
-const DB_PATH: &str = "./filesyncrs.redb";
+pub const SETTINGS_TABLE: TableDefinition<&str, &str> = TableDefinition::new("settings");
+pub const STATE_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("state");
+pub const HASH_CACHE_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("hash_cache");
+pub const PAIR_OPTIONS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("pair_options");
+pub const DOWNLOADS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("downloads");
+pub const UPLOADS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("uploads");
+pub const CHANGE_MARKERS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("change_markers");
+pub const PROFILES_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("profiles");
+pub const PAIR_PROFILES_TABLE: TableDefinition<&str, &str> = TableDefinition::new("pair_profiles");
+const DEFAULT_PATH: &str = "./filesyncrs.redb";
+
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// redb refuses to open the same file twice, so the whole process shares one handle
+static DATABASE: OnceLock<Database> = OnceLock::new();
+static OPEN_LOCK: Mutex<()> = Mutex::new(());
+
+fn database() -> Result<&'static Database, Error> {
+    if let Some(db) = DATABASE.get() {
+        return Ok(db);
+    }
+
+    let _guard = OPEN_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(db) = DATABASE.get() {
+        return Ok(db);
+    }
+
+    let db = Database::create(path())?;
+    Ok(DATABASE.get_or_init(|| db))
+}
+
+// Another file can be chosen only before the database is used
+pub fn set_path(path: impl Into<PathBuf>) -> anyhow::Result<()> {
+    PATH.set(path.into()).map_err(|_| anyhow::anyhow!("The database is already in use"))
+}
+
+pub fn path() -> &'static Path {
+    PATH.get_or_init(|| PathBuf::from(DEFAULT_PATH))
+}
+
+// Opens the database before its first use, so a process learns early that another one holds it
+pub fn open() -> Result<(), Error> {
+    database().map(|_| ())
+}
+
+// Commits wait for the disk, so async code runs database work on the blocking pool
+pub async fn blocking<T, F>(operation: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static
+{
+    Ok(tokio::task::spawn_blocking(operation).await??)
+}
 
 pub fn write(table: TableDefinition<&str, &str>, key: &str, value: &str) -> Result<(), Error> {
-    let db = Database::create(DB_PATH)?;
+    let db = database()?;
     let write_txn = db.begin_write()?;
     {
         let mut table = write_txn.open_table(table)?;
@@ -18,7 +71,7 @@ pub fn write(table: TableDefinition<&str, &str>, key: &str, value: &str) -> Resu
 }
 
 pub fn delete(table: TableDefinition<&str, &str>, key: &str) -> Result<(), Error> {
-    let db = Database::create(DB_PATH)?;
+    let db = database()?;
     let write_txn = db.begin_write()?;
     {
         let mut table = write_txn.open_table(table)?;
@@ -29,10 +82,26 @@ pub fn delete(table: TableDefinition<&str, &str>, key: &str) -> Result<(), Error
     Ok(())
 }
 
-pub fn read_as_hashmap(table: TableDefinition<&str, &str>) -> Result<BiHashMap<String, String>, Error> {
-    let db = Database::open(DB_PATH)?;
+pub fn read(table: TableDefinition<&str, &str>, key: &str) -> Result<Option<String>, Error> {
+    let db = database()?;
+    let txn = db.begin_read()?;
+    let table = match txn.open_table(table) {
+        Ok(table) => { table }
+        Err(TableError::TableDoesNotExist(_)) => { return Ok(None) }
+        Err(e) => { return Err(e.into()) }
+    };
+
+    Ok(table.get(key)?.map(|value| value.value().to_string()))
+}
+
+pub fn read_as_map(table: TableDefinition<&str, &str>) -> Result<BTreeMap<String, String>, Error> {
+    let db = database()?;
     let txn = db.begin_read()?;
-    let table = txn.open_table(table)?;
+    let table = match txn.open_table(table) {
+        Ok(table) => { table }
+        Err(TableError::TableDoesNotExist(_)) => { return Ok(BTreeMap::new()) }
+        Err(e) => { return Err(e.into()) }
+    };
 
     table
         .iter()?
@@ -41,4 +110,102 @@ pub fn read_as_hashmap(table: TableDefinition<&str, &str>) -> Result<BiHashMap<S
             Ok((key.value().to_string(), value.value().to_string()))
         })
         .collect()
-}
\ No newline at end of file
+}
+
+pub fn write_bytes(table: TableDefinition<&str, &[u8]>, key: &str, value: &[u8]) -> Result<(), Error> {
+    let db = database()?;
+    let write_txn = db.begin_write()?;
+    {
+        let mut table = write_txn.open_table(table)?;
+        table.insert(key, value)?;
+    }
+    write_txn.commit()?;
+
+    Ok(())
+}
+
+// All values go in one transaction
+pub fn write_many_bytes(table: TableDefinition<&str, &[u8]>, values: &[(String, Vec<u8>)]) -> Result<(), Error> {
+    let db = database()?;
+    let write_txn = db.begin_write()?;
+    {
+        let mut table = write_txn.open_table(table)?;
+        for (key, value) in values {
+            table.insert(key.as_str(), value.as_slice())?;
+        }
+    }
+    write_txn.commit()?;
+
+    Ok(())
+}
+
+pub fn read_bytes(table: TableDefinition<&str, &[u8]>, key: &str) -> Result<Option<Vec<u8>>, Error> {
+    let db = database()?;
+    let txn = db.begin_read()?;
+    let table = match txn.open_table(table) {
+        Ok(table) => { table }
+        Err(TableError::TableDoesNotExist(_)) => { return Ok(None) }
+        Err(e) => { return Err(e.into()) }
+    };
+
+    Ok(table.get(key)?.map(|value| value.value().to_vec()))
+}
+
+pub fn read_bytes_with_prefix(table: TableDefinition<&str, &[u8]>, prefix: &str) -> Result<HashMap<String, Vec<u8>>, Error> {
+    let db = database()?;
+    let txn = db.begin_read()?;
+    let table = match txn.open_table(table) {
+        Ok(table) => { table }
+        Err(TableError::TableDoesNotExist(_)) => { return Ok(HashMap::new()) }
+        Err(e) => { return Err(e.into()) }
+    };
+
+    let mut values = HashMap::new();
+    for item in table.range(prefix..)? {
+        let (key, value) = item?;
+        if !key.value().starts_with(prefix) {
+            break;
+        }
+        values.insert(key.value().to_string(), value.value().to_vec());
+    }
+
+    Ok(values)
+}
+
+// Keys of the path itself and of everything inside it, but not of its siblings sharing the prefix
+fn is_under_path(key: &str, path: &str) -> bool {
+    match key.strip_prefix(path) {
+        Some(rest) => { rest.is_empty() || rest.starts_with(path::is_separator) || path.ends_with(path::is_separator) }
+        None => { false }
+    }
+}
+
+pub fn read_bytes_under_path(table: TableDefinition<&str, &[u8]>, path: &str) -> Result<HashMap<String, Vec<u8>>, Error> {
+    let mut values = read_bytes_with_prefix(table, path)?;
+    values.retain(|key, _| is_under_path(key, path));
+    Ok(values)
+}
+
+pub fn delete_bytes(table: TableDefinition<&str, &[u8]>, key: &str) -> Result<(), Error> {
+    let db = database()?;
+    let write_txn = db.begin_write()?;
+    {
+        let mut table = write_txn.open_table(table)?;
+        table.remove(key)?;
+    }
+    write_txn.commit()?;
+
+    Ok(())
+}
+
+pub fn delete_under_path(table: TableDefinition<&str, &[u8]>, path: &str) -> Result<(), Error> {
+    let db = database()?;
+    let write_txn = db.begin_write()?;
+    {
+        let mut table = write_txn.open_table(table)?;
+        table.retain(|key, _| !is_under_path(key, path))?;
+    }
+    write_txn.commit()?;
+
+    Ok(())
+}
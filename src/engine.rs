@@ -0,0 +1,1828 @@
+use std::{cmp::Ordering, collections::{BTreeMap, HashMap, HashSet}, fs::Metadata, path::{Path, PathBuf}, sync::{Arc, Mutex, MutexGuard, PoisonError}, time::{Duration, Instant, UNIX_EPOCH}};
+
+use sha2::{Digest, Sha256};
+use tokio::{fs::{self, File, OpenOptions}, io::{AsyncReadExt, AsyncWriteExt}, sync::Semaphore};
+use tokio_util::sync::CancellationToken;
+use chrono::{DateTime, Local, NaiveTime, Utc};
+use futures::{SinkExt, StreamExt, channel::mpsc, stream};
+use anyhow::{Result, anyhow};
+use redb::TableDefinition;
+
+use crate::{SyncEvent, SyncState, backend::{self, HASH_ALGORITHM, RemoteBackend, RemoteEntry, RemoteFile, RemoteRead, TreeNode, UploadSource}, db::{self, CHANGE_MARKERS_TABLE, DOWNLOADS_TABLE, HASH_CACHE_TABLE, PAIR_OPTIONS_TABLE, STATE_TABLE, UPLOADS_TABLE}, profiles::ServerProfile, retry::{RetryPolicy, with_retry}};
+
+const METADATA_FILENAME: &str = ".syncmetadata";
+const PARTIAL_DOWNLOAD_SUFFIX: &str = ".filesync-part";
+pub const DEFAULT_MAX_DELETIONS: u64 = 50;
+pub const DEFAULT_MAX_TRANSFERS: usize = 4;
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+struct SyncMetadata {
+    files: HashMap<String, FileMetadata>
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct FileMetadata {
+    modified: DateTime<Utc>,
+    hash: Option<String>,
+    etag: Option<String>
+}
+
+#[derive(serde::Deserialize)]
+struct LegacySyncMetadata {
+    files: HashMap<String, DateTime<Utc>>
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct CachedHash {
+    size: u64,
+    modified: DateTime<Utc>,
+    hash: String
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct FileState {
+    local_modified: DateTime<Utc>,
+    remote_modified: DateTime<Utc>,
+    size: u64,
+    etag: Option<String>,
+    hash: Option<String>
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct ChangeMarker {
+    remote_tag: Option<String>,
+    local_fingerprint: String
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct PartialDownload {
+    remote_path: String,
+    etag: String
+}
+
+// Removes a partial download that can't be resumed, also when the download is dropped on cancellation
+struct PartialDownloadGuard {
+    partial_path: PathBuf,
+    keep: bool
+}
+
+impl Drop for PartialDownloadGuard {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.partial_path);
+        }
+    }
+}
+
+// New state of a synchronized file, written together with the rest of its pair
+struct PendingFileState {
+    pair_key: String,
+    local_path: String,
+    data: Vec<u8>
+}
+
+enum FileChange {
+    Unchanged,
+    LocalModified,
+    RemoteModified,
+    Conflict
+}
+
+// The engine's end of the SyncHandle
+struct RunControl {
+    cancel: CancellationToken,
+    resolutions: mpsc::UnboundedReceiver<(String, ConflictResolution)>
+}
+
+struct SyncWorker<B: RemoteBackend> {
+    backend: B,
+    output: mpsc::Sender<SyncEvent>,
+    syncmetadata: Mutex<SyncMetadata>,
+    conflicts: Mutex<Vec<Conflict>>,
+    deferred_conflicts: Mutex<HashSet<String>>,
+    reported_pairs: Mutex<HashSet<String>>,
+    file_states: Mutex<Vec<PendingFileState>>,
+    cancel: CancellationToken,
+    remote_snapshot: Mutex<RemoteSnapshot>,
+    transfers: Semaphore,
+    max_transfers: usize,
+    retry: RetryPolicy,
+    run_progress: Arc<Mutex<ProgressCounter>>,
+    upload_limiter: Option<Arc<RateLimiter>>,
+    download_limiter: Option<Arc<RateLimiter>>,
+    pair_limiters: Mutex<HashMap<String, PairLimiters>>,
+    unlimited_hours: Option<(NaiveTime, NaiveTime)>,
+    purpose: SyncPurpose,
+    conflict_policy: ConflictPolicy
+}
+
+impl<B: RemoteBackend> SyncWorker<B> {
+    async fn send(&self, event: SyncEvent) -> Result<()> {
+        self.output.clone().send(event).await?;
+        Ok(())
+    }
+
+    fn file_metadata(&self, remote_path: &str) -> Option<FileMetadata> {
+        lock(&self.syncmetadata).files.get(remote_path).cloned()
+    }
+
+    fn track_transfer(&self, pair_key: &str, local_path: &str, total: u64, direction: TransferDirection) -> ProgressTracker {
+        lock(&self.run_progress).total += total;
+
+        ProgressTracker {
+            output: self.output.clone(),
+            pair_key: pair_key.to_owned(),
+            local_path: local_path.to_owned(),
+            transfer: Arc::new(Mutex::new(ProgressCounter::new(total))),
+            run: self.run_progress.clone(),
+            throttle: self.throttle(pair_key, direction)
+        }
+    }
+
+    // Options are read once per pair before its transfers, limiters of the pair are shared by all of them
+    async fn load_pair_options(&self, pair_key: &str) -> Result<PairOptions> {
+        let options_key = pair_key.to_owned();
+        let pair_options = db::blocking(move || Ok(load_pair_options(&options_key))).await?;
+
+        lock(&self.pair_limiters).entry(pair_key.to_owned()).or_insert_with(|| PairLimiters {
+            upload: pair_options.upload_limit.map(|limit| Arc::new(RateLimiter::new(limit))),
+            download: pair_options.download_limit.map(|limit| Arc::new(RateLimiter::new(limit)))
+        });
+
+        Ok(pair_options)
+    }
+
+    fn throttle(&self, pair_key: &str, direction: TransferDirection) -> Throttle {
+        let pair_limiters = lock(&self.pair_limiters);
+        let pair_limiter = pair_limiters.get(pair_key).and_then(|pair_limiters| match direction {
+            TransferDirection::Upload => pair_limiters.upload.clone(),
+            TransferDirection::Download => pair_limiters.download.clone()
+        });
+        let global_limiter = match direction {
+            TransferDirection::Upload => &self.upload_limiter,
+            TransferDirection::Download => &self.download_limiter
+        };
+
+        Throttle {
+            limiters: global_limiter.iter().cloned().chain(pair_limiter).collect(),
+            unlimited_hours: self.unlimited_hours
+        }
+    }
+
+    async fn finish_transfer(&self, tracker: &ProgressTracker) -> Result<()> {
+        let run_progress = tracker.finish();
+        self.send(SyncEvent::TransferFinished(tracker.local_path.clone())).await?;
+        self.send(SyncEvent::Progress(run_progress)).await
+    }
+}
+
+struct ProgressCounter {
+    transferred: u64,
+    total: u64,
+    bytes_since_report: u64,
+    last_report: Instant,
+    bytes_per_second: f64
+}
+
+impl ProgressCounter {
+    fn new(total: u64) -> Self {
+        ProgressCounter {
+            transferred: 0,
+            total: total,
+            bytes_since_report: 0,
+            last_report: Instant::now(),
+            bytes_per_second: 0.0
+        }
+    }
+
+    // Returns true when enough time has passed to report the progress again
+    fn advance(&mut self, bytes: u64) -> bool {
+        self.transferred += bytes;
+        self.bytes_since_report += bytes;
+
+        let elapsed = self.last_report.elapsed();
+        if elapsed < PROGRESS_INTERVAL {
+            return false;
+        }
+
+        // Smoothed speed does not jump with every network hiccup
+        let current_speed = self.bytes_since_report as f64 / elapsed.as_secs_f64();
+        self.bytes_per_second = if self.bytes_per_second == 0.0 {
+            current_speed
+        } else {
+            self.bytes_per_second * 0.7 + current_speed * 0.3
+        };
+        self.bytes_since_report = 0;
+        self.last_report = Instant::now();
+        true
+    }
+
+    fn progress(&self) -> TransferProgress {
+        let remaining = self.total.saturating_sub(self.transferred);
+
+        TransferProgress {
+            transferred: self.transferred,
+            total: self.total,
+            bytes_per_second: self.bytes_per_second as u64,
+            eta: (self.bytes_per_second > 0.0).then(|| Duration::from_secs_f64(remaining as f64 / self.bytes_per_second))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ProgressTracker {
+    output: mpsc::Sender<SyncEvent>,
+    pair_key: String,
+    local_path: String,
+    transfer: Arc<Mutex<ProgressCounter>>,
+    run: Arc<Mutex<ProgressCounter>>,
+    throttle: Throttle
+}
+
+impl ProgressTracker {
+    pub(crate) fn advance(&self, bytes: u64) {
+        let transfer_progress = {
+            let mut transfer = lock(&self.transfer);
+            transfer.advance(bytes).then(|| transfer.progress())
+        };
+        let run_progress = {
+            let mut run = lock(&self.run);
+            run.advance(bytes).then(|| run.progress())
+        };
+
+        // Reports are informational, so one is dropped rather than waiting for a full channel
+        let mut output = self.output.clone();
+        if let Some(progress) = transfer_progress {
+            let _ = output.try_send(SyncEvent::TransferProgress(self.pair_key.clone(), self.local_path.clone(), progress));
+        }
+        if let Some(progress) = run_progress {
+            let _ = output.try_send(SyncEvent::Progress(progress));
+        }
+    }
+
+    pub(crate) async fn pace(&self, bytes: u64) {
+        self.throttle.pace(bytes).await;
+    }
+
+    // Every attempt of a transfer starts counting from the bytes already on the other side
+    pub(crate) fn rewind(&self, transferred: u64) {
+        let mut transfer = lock(&self.transfer);
+        let mut run = lock(&self.run);
+
+        run.transferred = run.transferred.saturating_sub(transfer.transferred) + transferred;
+        transfer.transferred = transferred;
+    }
+
+    fn finish(&self) -> TransferProgress {
+        let transfer = lock(&self.transfer);
+        let mut run = lock(&self.run);
+
+        // A failed transfer leaves in the run total only the bytes it has moved
+        run.total = run.total.saturating_sub(transfer.total.saturating_sub(transfer.transferred));
+        run.progress()
+    }
+}
+
+enum TreeEntry {
+    File,
+    Directory
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TransferDirection {
+    Upload,
+    Download
+}
+
+struct RateLimiter {
+    bytes_per_second: f64,
+    bucket: Mutex<TokenBucket>
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl RateLimiter {
+    fn new(kilobytes_per_second: u64) -> Self {
+        let bytes_per_second = (kilobytes_per_second.max(1) * 1024) as f64;
+
+        RateLimiter {
+            bytes_per_second: bytes_per_second,
+            bucket: Mutex::new(TokenBucket { tokens: bytes_per_second, updated: Instant::now() })
+        }
+    }
+
+    // Takes the bytes from the bucket and returns how long to wait to stay under the rate
+    fn reserve(&self, bytes: u64) -> Duration {
+        let mut bucket = lock(&self.bucket);
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.bytes_per_second;
+
+        // At most one second worth of traffic goes through in a burst
+        bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_second) - bytes as f64;
+        bucket.updated = now;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_second)
+        }
+    }
+}
+
+struct PairLimiters {
+    upload: Option<Arc<RateLimiter>>,
+    download: Option<Arc<RateLimiter>>
+}
+
+#[derive(Clone)]
+struct Throttle {
+    limiters: Vec<Arc<RateLimiter>>,
+    unlimited_hours: Option<(NaiveTime, NaiveTime)>
+}
+
+impl Throttle {
+    async fn pace(&self, bytes: u64) {
+        if self.limiters.is_empty() || self.is_unlimited_now() {
+            return;
+        }
+
+        // Every limiter is charged, the strictest one decides the delay
+        let delay = self.limiters
+            .iter()
+            .map(|limiter| limiter.reserve(bytes))
+            .max()
+            .unwrap_or_default();
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn is_unlimited_now(&self) -> bool {
+        let Some((from, to)) = self.unlimited_hours else {
+            return false;
+        };
+        let now = Local::now().time();
+
+        if from <= to {
+            from <= now && now < to
+        } else {
+            // The window goes over midnight, e.g. from 22:00 to 07:00
+            now >= from || now < to
+        }
+    }
+}
+
+#[derive(Default)]
+struct RemoteSnapshot {
+    entries: HashMap<String, RemoteEntry>,
+    listed_dirs: HashSet<String>
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Hash, Debug, Clone)]
+pub enum SyncPurpose {
+    Synchronize,
+    Check
+}
+
+#[derive(Hash, Debug, Clone)]
+pub struct SyncSettings {
+    pub max_transfers: usize,
+    pub max_attempts: u32,
+    // Limits are in KB/s and don't apply between the unlimited hours
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+    pub unlimited_hours: Option<(NaiveTime, NaiveTime)>
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        SyncSettings {
+            max_transfers: DEFAULT_MAX_TRANSFERS,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            upload_limit: None,
+            download_limit: None,
+            unlimited_hours: None
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct PairOptions {
+    pub max_deletions: Option<u64>,
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>
+}
+
+#[derive(serde::Deserialize)]
+struct LegacyPairOptions {
+    max_deletions: Option<u64>
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Hash, Debug, Clone, PartialEq)]
+pub struct SyncPair {
+    pub local_path: String,
+    pub remote_path: String,
+    // Name of the server profile to synchronize with
+    pub profile: String
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub pair_key: String,
+    pub local_path: String,
+    pub remote_path: String,
+    pub local_size: u64,
+    pub local_modified: DateTime<Utc>,
+    pub remote_size: u64,
+    pub remote_modified: DateTime<Utc>
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct TransferProgress {
+    pub transferred: u64,
+    pub total: u64,
+    pub bytes_per_second: u64,
+    pub eta: Option<Duration>
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncHandle {
+    pub resolver: mpsc::UnboundedSender<(String, ConflictResolution)>,
+    pub cancel: CancellationToken
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictResolution {
+    KeepLocal,
+    KeepRemote,
+    KeepBoth,
+    DecideLater
+}
+
+#[derive(Debug, Clone)]
+pub enum ConflictPolicy {
+    // Every conflict waits for an answer through the resolver of the SyncHandle
+    Ask,
+    // Conflicts are only reported and stay until a later run, nothing waits for answers
+    Defer,
+    // Like Defer, but conflicts answered since they were reported are resolved if they are still the same
+    Answered(Vec<(Conflict, ConflictResolution)>)
+}
+
+#[derive(Hash, Debug, Clone)]
+pub struct SyncEngine {
+    profiles: BTreeMap<String, ServerProfile>,
+    settings: SyncSettings
+}
+
+impl SyncEngine {
+    // Every pair is synchronized with the server of the profile it references
+    pub fn new(profiles: BTreeMap<String, ServerProfile>, settings: SyncSettings) -> Self {
+        SyncEngine {
+            profiles: profiles,
+            settings: settings
+        }
+    }
+
+    // Events of the synchronization go to the sender, SyncEvent::Finished is the last one
+    pub async fn run(self: &Self, events: mpsc::Sender<SyncEvent>, pairs: Arc<Vec<SyncPair>>, purpose: SyncPurpose, conflict_policy: ConflictPolicy) {
+        let mut output = events;
+
+        let (resolution_sender, resolutions) = mpsc::unbounded();
+        let mut control = RunControl {
+            cancel: CancellationToken::new(),
+            resolutions: resolutions
+        };
+        let _ = output.send(SyncEvent::Started(SyncHandle { resolver: resolution_sender, cancel: control.cancel.clone() })).await;
+
+        // One client per profile in use, servers are synchronized one after another
+        for (profile_name, profile_pairs) in group_pairs_by_profile(&pairs) {
+            if control.cancel.is_cancelled() {
+                report_pairs(&mut output, &profile_pairs, SyncState::Cancelled).await;
+                continue;
+            }
+
+            let result = match self.profiles.get(&profile_name) {
+                Some(profile) => { self.run_profile(profile, output.clone(), &profile_pairs, purpose.clone(), conflict_policy.clone(), &mut control).await }
+                None => { Err(anyhow!("No such profile")) }
+            };
+
+            if let Err(e) = result {
+                let _ = output.send(SyncEvent::Error(format!("{}: {}", profile_name, e))).await;
+                report_pairs(&mut output, &profile_pairs, SyncState::CantSynchronize).await;
+            }
+        }
+
+        let _ = output.send(SyncEvent::Finished).await;
+    }
+
+    // Pairs that may have changed on either side since they were synchronized
+    pub async fn poll_changed_pairs(self: &Self, pairs: &[SyncPair]) -> Result<Vec<String>> {
+        let mut changed_pairs = Vec::new();
+
+        for (profile_name, profile_pairs) in group_pairs_by_profile(pairs) {
+            let profile = self.profiles.get(&profile_name).ok_or_else(|| anyhow!("{}: No such profile", profile_name))?;
+            let result = self.poll_profile(profile, &profile_pairs).await;
+            changed_pairs.extend(result.map_err(|e| anyhow!("{}: {}", profile_name, e))?);
+        }
+
+        Ok(changed_pairs)
+    }
+
+    async fn run_profile(
+        self: &Self,
+        profile: &ServerProfile,
+        output: mpsc::Sender<SyncEvent>,
+        pairs: &[(String, String)],
+        purpose: SyncPurpose,
+        conflict_policy: ConflictPolicy,
+        control: &mut RunControl
+    ) -> Result<()> {
+        let backend = backend::connect(profile, self.settings.max_attempts);
+        run_sync(output, backend, pairs, purpose, conflict_policy, self.settings.clone(), control).await
+    }
+
+    async fn poll_profile(self: &Self, profile: &ServerProfile, pairs: &[(String, String)]) -> Result<Vec<String>> {
+        poll_changed_pairs(&backend::connect(profile, self.settings.max_attempts)?, pairs).await
+    }
+}
+
+// Keeps the order of pairs inside every profile
+fn group_pairs_by_profile(pairs: &[SyncPair]) -> BTreeMap<String, Vec<(String, String)>> {
+    let mut groups: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+
+    for pair in pairs {
+        groups.entry(pair.profile.clone()).or_default().push((pair.local_path.clone(), pair.remote_path.clone()));
+    }
+
+    groups
+}
+
+async fn report_pairs(output: &mut mpsc::Sender<SyncEvent>, pairs: &[(String, String)], syncstate: SyncState) {
+    for (key, _) in pairs {
+        let _ = output.send(SyncEvent::PairState(key.clone(), syncstate.clone())).await;
+    }
+}
+
+// Reconciliation knows the remote side only through the backend
+async fn run_sync<B: RemoteBackend>(
+    output: mpsc::Sender<SyncEvent>,
+    backend: Result<B>,
+    pairs: &[(String, String)],
+    purpose: SyncPurpose,
+    conflict_policy: ConflictPolicy,
+    settings: SyncSettings,
+    control: &mut RunControl
+) -> Result<()> {
+    let backend = backend.map_err(|_| anyhow!("Can't build client"))?;
+
+    let retry = RetryPolicy::new(settings.max_attempts);
+
+    if !backend.check_connection().await {
+        return Err(anyhow!("Can't open connection"));
+    }
+
+    let syncmetadata = load_metadata(&backend, retry).await.unwrap_or_default();
+    let max_transfers = settings.max_transfers.max(1);
+    let worker = SyncWorker {
+        backend: backend,
+        output: output,
+        syncmetadata: Mutex::new(syncmetadata),
+        conflicts: Mutex::new(Vec::new()),
+        deferred_conflicts: Mutex::new(HashSet::new()),
+        reported_pairs: Mutex::new(HashSet::new()),
+        file_states: Mutex::new(Vec::new()),
+        cancel: control.cancel.clone(),
+        remote_snapshot: Mutex::new(RemoteSnapshot::default()),
+        transfers: Semaphore::new(max_transfers),
+        max_transfers: max_transfers,
+        retry: retry,
+        run_progress: Arc::new(Mutex::new(ProgressCounter::new(0))),
+        upload_limiter: settings.upload_limit.map(|limit| Arc::new(RateLimiter::new(limit))),
+        download_limiter: settings.download_limit.map(|limit| Arc::new(RateLimiter::new(limit))),
+        pair_limiters: Mutex::new(HashMap::new()),
+        unlimited_hours: settings.unlimited_hours,
+        purpose: purpose,
+        conflict_policy: conflict_policy
+    };
+
+    // Dropping the running futures aborts in-flight requests and transfers
+    let result = tokio::select! {
+        result = synchronize_files(&worker, pairs) => result,
+        _ = worker.cancel.cancelled() => return finish_cancelled_sync(&worker, pairs).await
+    };
+
+    // Pairs are already reported one by one
+    if let Err(e) = result {
+        let _ = worker.send(SyncEvent::Error(e.to_string())).await;
+        return Ok(());
+    }
+
+    let result = tokio::select! {
+        result = resolve_conflicts(&worker, pairs, &mut control.resolutions) => result,
+        _ = worker.cancel.cancelled() => return finish_cancelled_sync(&worker, pairs).await
+    };
+
+    if let Err(e) = result {
+        let _ = worker.send(SyncEvent::Error(e.to_string())).await;
+    }
+
+    // Pairs with conflicts left for later still keep the files resolved so far
+    if let Err(e) = write_all_file_states(&worker).await {
+        let _ = worker.send(SyncEvent::Error(e.to_string())).await;
+    }
+
+    if worker.cancel.is_cancelled() {
+        return finish_cancelled_sync(&worker, pairs).await;
+    }
+
+    if let SyncPurpose::Synchronize = worker.purpose {
+        let syncmetadata = lock(&worker.syncmetadata).clone();
+        if let Err(e) = save_and_upload_metadata(&worker.backend, &syncmetadata).await {
+            let _ = worker.send(SyncEvent::Error(e.to_string())).await;
+        }
+    }
+
+    Ok(())
+}
+
+async fn synchronize_files<B: RemoteBackend>(worker: &SyncWorker<B>, pairs: &[(String, String)]) -> Result<()> {
+    prefetch_remote_state(worker, pairs).await;
+
+    stream::iter(pairs.iter())
+        .map(|(key, value)| synchronize_and_report_pair(worker, key, value))
+        .buffer_unordered(worker.max_transfers)
+        .collect::<Vec<Result<()>>>()
+        .await
+        .into_iter()
+        .collect()
+}
+
+async fn finish_cancelled_sync<B: RemoteBackend>(worker: &SyncWorker<B>, pairs: &[(String, String)]) -> Result<()> {
+    // Metadata is not uploaded, it may describe only a part of this synchronization
+    let reported_pairs = lock(&worker.reported_pairs).clone();
+
+    if let Err(e) = write_all_file_states(worker).await {
+        let _ = worker.send(SyncEvent::Error(e.to_string())).await;
+    }
+
+    for (key, _) in pairs.iter().filter(|(key, _)| !reported_pairs.contains(key)) {
+        let _ = worker.send(SyncEvent::PairState(key.clone(), SyncState::Cancelled)).await;
+    }
+
+    Ok(())
+}
+
+async fn synchronize_and_report_pair<B: RemoteBackend>(worker: &SyncWorker<B>, local_path: &str, remote_path: &str) -> Result<()> {
+    let mut syncstate = synchronize_pair(worker, local_path, remote_path).await;
+    // Files finished before a failure keep their states
+    if let Err(e) = write_file_states(worker, local_path).await {
+        syncstate = Err(e);
+    }
+    lock(&worker.reported_pairs).insert(local_path.to_owned());
+
+    match syncstate {
+        Ok(syncstate) => {
+            if syncstate == SyncState::Synchronized {
+                save_change_marker(worker, local_path, remote_path).await;
+            }
+            worker.send(SyncEvent::PairState(local_path.to_owned(), syncstate)).await?;
+        }
+        Err(e) => {
+            worker.send(SyncEvent::PairState(local_path.to_owned(), SyncState::CantSynchronize)).await?;
+            worker.send(SyncEvent::Error(e.to_string())).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn synchronize_pair<B: RemoteBackend>(worker: &SyncWorker<B>, local_path: &str, remote_path: &str) -> Result<SyncState> {
+    let pair_options = worker.load_pair_options(local_path).await?;
+
+    if is_directory_pair(worker, local_path, remote_path).await? {
+        synchronize_directory(worker, &pair_options, local_path, local_path, remote_path).await
+    } else if !is_local_file_exist(local_path).await && !is_download_possible(local_path).await {
+        sync_impossible(local_path, "Not all dirs in path exist")
+    } else {
+        remove_stale_download(local_path).await?;
+        synchronize_file(worker, local_path, local_path, remote_path).await
+    }
+}
+
+async fn synchronize_directory<B: RemoteBackend>(worker: &SyncWorker<B>, pair_options: &PairOptions, pair_key: &str, local_dir: &str, remote_dir: &str) -> Result<SyncState> {
+    let local_exist = is_local_file_exist(local_dir).await;
+    let remote_exist = is_remote_file_exist(worker, remote_dir).await?;
+
+    if !local_exist && !remote_exist {
+        return sync_impossible(local_dir, "Local and remote directories don't exist");
+    }
+
+    let mut pair_syncstate = SyncState::Synchronized;
+
+    if !local_exist {
+        pair_syncstate = sync_directory_through_downloading(worker, local_dir).await?;
+    } else if !remote_exist {
+        pair_syncstate = sync_directory_through_uploading(worker, remote_dir).await?;
+    }
+
+    let local_entries = if local_exist { list_local_tree(local_dir).await? } else { BTreeMap::new() };
+    let remote_entries = if remote_exist { list_remote_tree(worker, remote_dir).await? } else { BTreeMap::new() };
+    let state_prefix = local_dir.to_owned();
+    let known_paths: HashSet<String> = db::blocking(move || db::read_bytes_under_path(STATE_TABLE, &state_prefix)).await?.into_keys().collect();
+
+    let mut relative_paths: Vec<&String> = local_entries.keys().chain(remote_entries.keys()).collect();
+    relative_paths.sort();
+    relative_paths.dedup();
+
+    if let SyncPurpose::Synchronize = worker.purpose {
+        // Counts every file synchronized before and missing on one side, even if it will be restored instead
+        let deletions = relative_paths
+            .iter()
+            .filter(|relative_path| matches!(
+                (local_entries.get(**relative_path), remote_entries.get(**relative_path)),
+                (Some(TreeEntry::File), None) | (None, Some(TreeEntry::File))
+            ))
+            .filter(|relative_path| known_paths.contains(&join_local_path(local_dir, relative_path)))
+            .count() as u64;
+        let max_deletions = pair_options.max_deletions.unwrap_or(DEFAULT_MAX_DELETIONS);
+
+        if deletions > max_deletions {
+            return sync_impossible(pair_key, &format!("{} deletions exceed the limit of {} for this pair", deletions, max_deletions));
+        }
+    }
+
+    let mut remote_dirs_to_delete = Vec::new();
+    let mut local_dirs_to_delete = Vec::new();
+    let mut file_paths = Vec::new();
+    let mut syncstates = Vec::new();
+
+    // Directories go first and one by one, so every file below finds its parents ready
+    for relative_path in relative_paths {
+        let local_path = join_local_path(local_dir, relative_path);
+        let remote_path = join_remote_path(remote_dir, relative_path);
+        let was_synchronized = || {
+            let prefix = join_local_path(local_dir, &format!("{}/", relative_path));
+            known_paths.iter().any(|known_path| known_path.starts_with(&prefix))
+        };
+
+        let syncstate = match (local_entries.get(relative_path), remote_entries.get(relative_path)) {
+            (Some(TreeEntry::Directory), None) if was_synchronized() => {
+                local_dirs_to_delete.push(local_path);
+                sync_directory_through_local_deleting(worker)
+            }
+            (None, Some(TreeEntry::Directory)) if was_synchronized() => {
+                remote_dirs_to_delete.push(remote_path);
+                sync_directory_through_remote_deleting(worker)
+            }
+            (Some(TreeEntry::Directory), None) => {
+                sync_directory_through_uploading(worker, &remote_path).await
+            }
+            (None, Some(TreeEntry::Directory)) => {
+                sync_directory_through_downloading(worker, &local_path).await
+            }
+            (Some(TreeEntry::Directory), Some(TreeEntry::Directory)) => {
+                Ok(SyncState::Synchronized)
+            }
+            (Some(TreeEntry::Directory), Some(TreeEntry::File)) | (Some(TreeEntry::File), Some(TreeEntry::Directory)) => {
+                sync_impossible(&local_path, "File on one side is a directory on the other")
+            }
+            _ => {
+                file_paths.push((local_path, remote_path));
+                continue;
+            }
+        };
+
+        syncstates.push(syncstate);
+    }
+
+    let file_syncstates: Vec<Result<SyncState>> = stream::iter(file_paths.iter())
+        .map(|(local_path, remote_path)| synchronize_file(worker, pair_key, local_path, remote_path))
+        .buffer_unordered(worker.max_transfers)
+        .collect()
+        .await;
+    syncstates.extend(file_syncstates);
+
+    for syncstate in syncstates {
+        match syncstate {
+            Ok(syncstate) => {
+                pair_syncstate = merge_syncstates(pair_syncstate, syncstate);
+            }
+            Err(e) => {
+                pair_syncstate = SyncState::CantSynchronize;
+                worker.send(SyncEvent::Error(e.to_string())).await?;
+            }
+        }
+    }
+
+    if let SyncPurpose::Synchronize = worker.purpose {
+        // Directories stay in place if they got new files during this synchronization
+        for local_path in local_dirs_to_delete.iter().rev() {
+            let _ = fs::remove_dir(local_path).await;
+        }
+        for remote_path in remote_dirs_to_delete.iter().rev() {
+            if worker.backend.list(remote_path, false).await?.unwrap_or_default().is_empty() {
+                worker.backend.delete(remote_path).await?;
+            }
+        }
+    }
+
+    Ok(pair_syncstate)
+}
+
+async fn synchronize_file<B: RemoteBackend>(worker: &SyncWorker<B>, pair_key: &str, local_path: &str, remote_path: &str) -> Result<SyncState> {
+    let local_exist = is_local_file_exist(local_path).await;
+    let remote_exist = is_remote_file_exist(worker, remote_path).await?;
+
+    if local_exist && remote_exist {
+        match classify_file_change(worker, local_path, remote_path).await? {
+            FileChange::LocalModified => {
+                return sync_through_uploading(worker, pair_key, local_path, remote_path).await;
+            },
+            FileChange::RemoteModified => {
+                return sync_through_downloading(worker, pair_key, local_path, remote_path).await;
+            },
+            FileChange::Conflict => {
+                return report_conflict(worker, pair_key, local_path, remote_path).await;
+            },
+            FileChange::Unchanged => {
+                if let SyncPurpose::Synchronize = worker.purpose {
+                    return finish_file_sync(worker, pair_key, local_path, remote_path).await;
+                }
+                return Ok(SyncState::Synchronized);
+            }
+        }
+    } else if local_exist && !remote_exist {
+        if let Some(file_state) = load_file_state(local_path).await?
+            && !is_local_changed(&file_state, &get_local_file_info(local_path).await?)? {
+            return sync_through_local_deleting(worker, local_path, remote_path).await;
+        }
+        return sync_through_uploading(worker, pair_key, local_path, remote_path).await;
+    } else if !local_exist && remote_exist {
+        if let Some(file_state) = load_file_state(local_path).await?
+            && !is_remote_changed(&file_state, &get_remote_file_info(worker, remote_path).await?) {
+            return sync_through_remote_deleting(worker, local_path, remote_path).await;
+        }
+        return sync_through_downloading(worker, pair_key, local_path, remote_path).await;
+    }
+    sync_impossible(local_path, "Local and remote files don't exist")
+}
+
+// SYNCHRONIZE WAYS
+async fn sync_through_downloading<B: RemoteBackend>(worker: &SyncWorker<B>, pair_key: &str, local_path: &str, remote_path: &str) -> Result<SyncState> {
+    match &worker.purpose {
+        SyncPurpose::Synchronize => {
+            if let Some(parent) = Path::new(local_path).parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let _permit = worker.transfers.acquire().await?;
+            download_with_progress(worker, pair_key, local_path, remote_path).await?;
+            finish_file_sync(worker, pair_key, local_path, remote_path).await
+        }
+        SyncPurpose::Check => {
+            Ok(SyncState::UnsynchronizedLocal)
+        }
+    }
+}
+
+async fn sync_through_uploading<B: RemoteBackend>(worker: &SyncWorker<B>, pair_key: &str, local_path: &str, remote_path: &str) -> Result<SyncState> {
+    match &worker.purpose {
+        SyncPurpose::Synchronize => {
+            let checksum = get_local_file_hash(local_path).await?;
+            let _permit = worker.transfers.acquire().await?;
+            upload_with_progress(worker, pair_key, local_path, remote_path, &checksum).await?;
+            finish_file_sync(worker, pair_key, local_path, remote_path).await
+        }
+        SyncPurpose::Check => {
+            Ok(SyncState::UnsynchronizedRemote)
+        }
+    }
+}
+
+async fn sync_directory_through_downloading<B: RemoteBackend>(worker: &SyncWorker<B>, local_dir: &str) -> Result<SyncState> {
+    match &worker.purpose {
+        SyncPurpose::Synchronize => {
+            if !is_download_possible(local_dir).await {
+                return sync_impossible(local_dir, "Not all dirs in path exist");
+            }
+            fs::create_dir(local_dir).await?;
+            Ok(SyncState::Synchronized)
+        }
+        SyncPurpose::Check => {
+            Ok(SyncState::UnsynchronizedLocal)
+        }
+    }
+}
+
+async fn sync_directory_through_uploading<B: RemoteBackend>(worker: &SyncWorker<B>, remote_dir: &str) -> Result<SyncState> {
+    match &worker.purpose {
+        SyncPurpose::Synchronize => {
+            worker.backend.mkdir(remote_dir).await?;
+            lock(&worker.remote_snapshot).entries.insert(snapshot_key(remote_dir), RemoteEntry::Directory);
+            Ok(SyncState::Synchronized)
+        }
+        SyncPurpose::Check => {
+            Ok(SyncState::UnsynchronizedRemote)
+        }
+    }
+}
+
+async fn sync_through_local_deleting<B: RemoteBackend>(worker: &SyncWorker<B>, local_path: &str, remote_path: &str) -> Result<SyncState> {
+    match &worker.purpose {
+        SyncPurpose::Synchronize => {
+            fs::remove_file(local_path).await?;
+            forget_file(worker, local_path, remote_path).await?;
+            Ok(SyncState::Synchronized)
+        }
+        SyncPurpose::Check => {
+            Ok(SyncState::UnsynchronizedLocal)
+        }
+    }
+}
+
+async fn sync_through_remote_deleting<B: RemoteBackend>(worker: &SyncWorker<B>, local_path: &str, remote_path: &str) -> Result<SyncState> {
+    match &worker.purpose {
+        SyncPurpose::Synchronize => {
+            worker.backend.delete(remote_path).await?;
+            lock(&worker.remote_snapshot).entries.remove(&snapshot_key(remote_path));
+            forget_file(worker, local_path, remote_path).await?;
+            Ok(SyncState::Synchronized)
+        }
+        SyncPurpose::Check => {
+            Ok(SyncState::UnsynchronizedRemote)
+        }
+    }
+}
+
+fn sync_directory_through_local_deleting<B: RemoteBackend>(worker: &SyncWorker<B>) -> Result<SyncState> {
+    match &worker.purpose {
+        SyncPurpose::Synchronize => Ok(SyncState::Synchronized),
+        SyncPurpose::Check => Ok(SyncState::UnsynchronizedLocal)
+    }
+}
+
+fn sync_directory_through_remote_deleting<B: RemoteBackend>(worker: &SyncWorker<B>) -> Result<SyncState> {
+    match &worker.purpose {
+        SyncPurpose::Synchronize => Ok(SyncState::Synchronized),
+        SyncPurpose::Check => Ok(SyncState::UnsynchronizedRemote)
+    }
+}
+
+async fn finish_file_sync<B: RemoteBackend>(worker: &SyncWorker<B>, pair_key: &str, local_path: &str, remote_path: &str) -> Result<SyncState> {
+    let file_state = save_file_state(worker, pair_key, local_path, remote_path).await?;
+
+    lock(&worker.syncmetadata)
+        .files
+        .insert(remote_path.to_owned(), FileMetadata {
+            modified: file_state.local_modified,
+            hash: file_state.hash,
+            etag: file_state.etag
+        });
+
+    Ok(SyncState::Synchronized)
+}
+
+async fn forget_file<B: RemoteBackend>(worker: &SyncWorker<B>, local_path: &str, remote_path: &str) -> Result<()> {
+    lock(&worker.syncmetadata).files.remove(remote_path);
+    lock(&worker.file_states).retain(|file_state| file_state.local_path != local_path);
+
+    let local_path = local_path.to_owned();
+    db::blocking(move || {
+        db::delete_bytes(STATE_TABLE, &local_path)?;
+        db::delete_bytes(HASH_CACHE_TABLE, &local_path)?;
+        db::delete_bytes(DOWNLOADS_TABLE, &local_path)?;
+        db::delete_bytes(UPLOADS_TABLE, &local_path)
+    }).await
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+async fn load_bytes(table: TableDefinition<'static, &'static str, &'static [u8]>, key: &str) -> Result<Option<Vec<u8>>> {
+    let key = key.to_owned();
+    db::blocking(move || db::read_bytes(table, &key)).await
+}
+
+async fn store_bytes(table: TableDefinition<'static, &'static str, &'static [u8]>, key: &str, value: Vec<u8>) -> Result<()> {
+    let key = key.to_owned();
+    db::blocking(move || db::write_bytes(table, &key, &value)).await
+}
+
+fn sync_impossible(local_path: &str, msg: &str) -> Result<SyncState> {
+    Err(anyhow!("For file {}: {}", local_path, msg))
+}
+
+fn merge_syncstates(current: SyncState, next: SyncState) -> SyncState {
+    match (current, next) {
+        (SyncState::CantSynchronize, _) | (_, SyncState::CantSynchronize) => SyncState::CantSynchronize,
+        (SyncState::Conflict, _) | (_, SyncState::Conflict) => SyncState::Conflict,
+        (SyncState::Synchronized, next) => next,
+        (current, _) => current
+    }
+}
+
+
+// CONFLICTS
+async fn report_conflict<B: RemoteBackend>(worker: &SyncWorker<B>, pair_key: &str, local_path: &str, remote_path: &str) -> Result<SyncState> {
+    if matches!(worker.purpose, SyncPurpose::Check) || lock(&worker.deferred_conflicts).contains(local_path) {
+        return Ok(SyncState::Conflict);
+    }
+
+    let metadata = get_local_file_info(local_path).await?;
+    let remote_file = get_remote_file_info(worker, remote_path).await?;
+
+    let conflict = Conflict {
+        pair_key: pair_key.to_owned(),
+        local_path: local_path.to_owned(),
+        remote_path: remote_path.to_owned(),
+        local_size: metadata.len(),
+        local_modified: metadata.modified()?.into(),
+        remote_size: remote_file.size,
+        remote_modified: remote_file.modified
+    };
+
+    match &worker.conflict_policy {
+        // Only conflicts somebody is going to answer keep the run waiting
+        ConflictPolicy::Ask => { lock(&worker.conflicts).push(conflict.clone()); }
+        ConflictPolicy::Answered(answers) => {
+            let answer = answers
+                .iter()
+                .find(|(answered, resolution)| *answered == conflict && !matches!(resolution, ConflictResolution::DecideLater));
+
+            if let Some((_, resolution)) = answer {
+                apply_conflict_resolution(worker, &conflict, *resolution).await?;
+                return Ok(SyncState::Synchronized);
+            }
+        }
+        ConflictPolicy::Defer => {}
+    }
+    worker.send(SyncEvent::Conflict(conflict)).await?;
+    Ok(SyncState::Conflict)
+}
+
+async fn resolve_conflicts<B: RemoteBackend>(
+    worker: &SyncWorker<B>,
+    pairs: &[(String, String)],
+    resolutions: &mut mpsc::UnboundedReceiver<(String, ConflictResolution)>
+) -> Result<()> {
+    while !lock(&worker.conflicts).is_empty() {
+        let Some((local_path, resolution)) = resolutions.next().await else {
+            break;
+        };
+        let conflict = {
+            let mut conflicts = lock(&worker.conflicts);
+            let Some(index) = conflicts.iter().position(|conflict| conflict.local_path == local_path) else {
+                continue;
+            };
+            conflicts.remove(index)
+        };
+
+        if let Err(e) = apply_conflict_resolution(worker, &conflict, resolution).await {
+            worker.send(SyncEvent::Error(e.to_string())).await?;
+        }
+
+        if lock(&worker.conflicts).iter().any(|pending| pending.pair_key == conflict.pair_key) {
+            continue;
+        }
+
+        if let Some((key, value)) = pairs.iter().find(|(key, _)| *key == conflict.pair_key) {
+            // The next pass compares against the states of the resolved files
+            write_file_states(worker, key).await?;
+            synchronize_and_report_pair(worker, key, value).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply_conflict_resolution<B: RemoteBackend>(worker: &SyncWorker<B>, conflict: &Conflict, resolution: ConflictResolution) -> Result<()> {
+    let _permit = worker.transfers.acquire().await?;
+
+    match resolution {
+        ConflictResolution::KeepLocal => {
+            let checksum = get_local_file_hash(&conflict.local_path).await?;
+            upload_with_progress(worker, &conflict.pair_key, &conflict.local_path, &conflict.remote_path, &checksum).await?;
+        }
+        ConflictResolution::KeepRemote => {
+            download_with_progress(worker, &conflict.pair_key, &conflict.local_path, &conflict.remote_path).await?;
+        }
+        ConflictResolution::KeepBoth => {
+            fs::rename(&conflict.local_path, conflict_copy_path(&conflict.local_path)).await?;
+            download_with_progress(worker, &conflict.pair_key, &conflict.local_path, &conflict.remote_path).await?;
+        }
+        ConflictResolution::DecideLater => {
+            lock(&worker.deferred_conflicts).insert(conflict.local_path.clone());
+            return Ok(());
+        }
+    }
+
+    finish_file_sync(worker, &conflict.pair_key, &conflict.local_path, &conflict.remote_path).await?;
+    Ok(())
+}
+
+fn conflict_copy_path(local_path: &str) -> String {
+    let path = Path::new(local_path);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let suffix = Local::now().format("%Y-%m-%d %H%M%S");
+
+    let file_name = match path.extension() {
+        Some(extension) => format!("{} (conflict {}).{}", stem, suffix, extension.to_string_lossy()),
+        None => format!("{} (conflict {})", stem, suffix)
+    };
+
+    path.with_file_name(file_name).to_string_lossy().into_owned()
+}
+
+
+// DIRECTORY TREES
+async fn is_directory_pair<B: RemoteBackend>(worker: &SyncWorker<B>, local_path: &str, remote_path: &str) -> Result<bool> {
+    if Path::new(local_path).is_dir() {
+        return Ok(true);
+    }
+
+    if is_local_file_exist(local_path).await {
+        return Ok(false);
+    }
+
+    Ok(matches!(get_remote_entry(worker, remote_path).await?, Some(RemoteEntry::Directory)))
+}
+
+async fn list_local_tree(local_dir: &str) -> Result<BTreeMap<String, TreeEntry>> {
+    let mut entries = BTreeMap::new();
+    let mut pending_dirs = vec![String::new()];
+
+    while let Some(relative_dir) = pending_dirs.pop() {
+        let mut read_dir = fs::read_dir(join_local_path(local_dir, &relative_dir)).await?;
+
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            let Some(name) = dir_entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            let relative_path = join_relative_path(&relative_dir, &name);
+
+            if is_partial_download(&name) {
+                // Left by an interrupted download, kept only while it can be resumed
+                if !is_resumable_download(&partial_download_target(&dir_entry.path())).await? {
+                    fs::remove_file(dir_entry.path()).await?;
+                }
+                continue;
+            }
+
+            if fs::metadata(dir_entry.path()).await?.is_dir() {
+                pending_dirs.push(relative_path.clone());
+                entries.insert(relative_path, TreeEntry::Directory);
+            } else {
+                entries.insert(relative_path, TreeEntry::File);
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+async fn list_remote_tree<B: RemoteBackend>(worker: &SyncWorker<B>, remote_dir: &str) -> Result<BTreeMap<String, TreeEntry>> {
+    let root = snapshot_key(remote_dir);
+    let listing = list_remote_dir(worker, &root, true).await?.unwrap_or_default();
+
+    let mut entries = BTreeMap::new();
+
+    for (path, entry) in listing {
+        let Some(relative_path) = strip_remote_root(&path, &root) else {
+            continue;
+        };
+
+        if relative_path.is_empty() || relative_path == METADATA_FILENAME {
+            continue;
+        }
+
+        let entry = match entry {
+            RemoteEntry::File(_) => TreeEntry::File,
+            RemoteEntry::Directory => TreeEntry::Directory
+        };
+        entries.insert(relative_path, entry);
+    }
+
+    Ok(entries)
+}
+
+fn join_relative_path(relative_dir: &str, name: &str) -> String {
+    if relative_dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", relative_dir, name)
+    }
+}
+
+fn join_local_path(local_dir: &str, relative_path: &str) -> String {
+    Path::new(local_dir).join(relative_path).to_string_lossy().into_owned()
+}
+
+fn join_remote_path(remote_dir: &str, relative_path: &str) -> String {
+    let remote_dir = remote_dir.trim_end_matches('/');
+
+    if relative_path.is_empty() {
+        remote_dir.to_owned()
+    } else {
+        format!("{}/{}", remote_dir, relative_path)
+    }
+}
+
+fn strip_remote_root(path: &str, remote_dir: &str) -> Option<String> {
+    let relative_path = path
+        .trim_end_matches('/')
+        .strip_prefix(remote_dir.trim_end_matches('/'))?;
+
+    if !relative_path.is_empty() && !relative_path.starts_with('/') {
+        return None;
+    }
+
+    Some(relative_path.trim_start_matches('/').to_owned())
+}
+
+
+// REMOTE SNAPSHOT
+async fn prefetch_remote_state<B: RemoteBackend>(worker: &SyncWorker<B>, pairs: &[(String, String)]) {
+    let parent_dirs: HashSet<String> = pairs
+        .iter()
+        .map(|(_, remote_path)| remote_parent(&snapshot_key(remote_path)))
+        .collect();
+
+    // A failed listing is not fatal, paths below it are looked up one by one later
+    stream::iter(parent_dirs.iter())
+        .map(|dir_path| list_remote_dir(worker, dir_path, false))
+        .buffer_unordered(worker.max_transfers)
+        .for_each(|_| async {})
+        .await;
+}
+
+async fn list_remote_dir<B: RemoteBackend>(worker: &SyncWorker<B>, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+    let dir_key = snapshot_key(dir_path);
+
+    let Some(entries) = worker.backend.list(&dir_key, recursive).await? else {
+        lock(&worker.remote_snapshot).listed_dirs.insert(dir_key);
+        return Ok(None);
+    };
+
+    let mut snapshot = lock(&worker.remote_snapshot);
+    snapshot.entries.insert(dir_key.clone(), RemoteEntry::Directory);
+    snapshot.listed_dirs.insert(dir_key);
+    for (path, entry) in &entries {
+        if recursive && matches!(entry, RemoteEntry::Directory) {
+            snapshot.listed_dirs.insert(path.clone());
+        }
+        snapshot.entries.insert(path.clone(), entry.clone());
+    }
+
+    Ok(Some(entries))
+}
+
+async fn get_remote_entry<B: RemoteBackend>(worker: &SyncWorker<B>, remote_path: &str) -> Result<Option<RemoteEntry>> {
+    let key = snapshot_key(remote_path);
+
+    {
+        let snapshot = lock(&worker.remote_snapshot);
+        if let Some(entry) = snapshot.entries.get(&key) {
+            return Ok(Some(entry.clone()));
+        }
+        if snapshot.listed_dirs.contains(&remote_parent(&key)) {
+            return Ok(None);
+        }
+    }
+
+    refresh_remote_entry(worker, remote_path).await
+}
+
+async fn refresh_remote_entry<B: RemoteBackend>(worker: &SyncWorker<B>, remote_path: &str) -> Result<Option<RemoteEntry>> {
+    let key = snapshot_key(remote_path);
+
+    let entry = worker.backend.stat(remote_path).await?;
+
+    let mut snapshot = lock(&worker.remote_snapshot);
+    match &entry {
+        Some(entry) => snapshot.entries.insert(key, entry.clone()),
+        None => snapshot.entries.remove(&key)
+    };
+
+    Ok(entry)
+}
+
+fn snapshot_key(remote_path: &str) -> String {
+    format!("/{}", remote_path.trim_matches('/'))
+}
+
+fn remote_parent(key: &str) -> String {
+    match key.rsplit_once('/') {
+        Some((parent, _)) if !parent.is_empty() => parent.to_owned(),
+        _ => String::from("/")
+    }
+}
+
+
+// LAST SYNCHRONIZED FILE STATES
+async fn classify_file_change<B: RemoteBackend>(worker: &SyncWorker<B>, local_path: &str, remote_path: &str) -> Result<FileChange> {
+    let metadata = get_local_file_info(local_path).await?;
+    let remote_file = get_remote_file_info(worker, remote_path).await?;
+    let file_state = load_file_state(local_path).await?;
+
+    let file_change = match &file_state {
+        Some(file_state) => match (is_local_changed(file_state, &metadata)?, is_remote_changed(file_state, &remote_file)) {
+            (false, false) => FileChange::Unchanged,
+            (true, false) => FileChange::LocalModified,
+            (false, true) => FileChange::RemoteModified,
+            (true, true) => FileChange::Conflict
+        },
+        None => match compare_modified_time(worker, remote_path, &metadata, &get_exact_remote_file_info(worker, remote_path).await?)? {
+            Ordering::Greater => FileChange::LocalModified,
+            Ordering::Less => FileChange::RemoteModified,
+            Ordering::Equal => FileChange::Unchanged
+        }
+    };
+
+    if let FileChange::Unchanged = file_change {
+        return Ok(file_change);
+    }
+
+    if metadata.len() == remote_file.size
+        && let Some(remote_hash) = get_remote_file_hash(worker, remote_path, file_state.as_ref(), &remote_file).await?
+        && remote_hash == get_local_file_hash(local_path).await? {
+        return Ok(FileChange::Unchanged);
+    }
+
+    Ok(file_change)
+}
+
+fn is_local_changed(file_state: &FileState, metadata: &Metadata) -> Result<bool> {
+    let local_modified: DateTime<Utc> = metadata.modified()?.into();
+    Ok(local_modified != file_state.local_modified || metadata.len() != file_state.size)
+}
+
+fn is_remote_changed(file_state: &FileState, remote_file: &RemoteFile) -> bool {
+    match (&file_state.etag, &remote_file.etag) {
+        (Some(etag), Some(tag)) => etag != tag,
+        _ => remote_file.modified != file_state.remote_modified || remote_file.size != file_state.size
+    }
+}
+
+async fn save_file_state<B: RemoteBackend>(worker: &SyncWorker<B>, pair_key: &str, local_path: &str, remote_path: &str) -> Result<FileState> {
+    let metadata = get_local_file_info(local_path).await?;
+    let remote_file = get_remote_file_info(worker, remote_path).await?;
+
+    let file_state = FileState {
+        local_modified: metadata.modified()?.into(),
+        remote_modified: remote_file.modified,
+        size: metadata.len(),
+        etag: remote_file.etag,
+        hash: Some(get_local_file_hash(local_path).await?)
+    };
+
+    // Unchanged files keep their state, so a run without changes writes nothing
+    if load_file_state(local_path).await?.as_ref() != Some(&file_state) {
+        lock(&worker.file_states).push(PendingFileState {
+            pair_key: pair_key.to_owned(),
+            local_path: local_path.to_owned(),
+            data: postcard::to_allocvec(&file_state)?
+        });
+    }
+    Ok(file_state)
+}
+
+async fn write_file_states<B: RemoteBackend>(worker: &SyncWorker<B>, pair_key: &str) -> Result<()> {
+    let file_states = lock(&worker.file_states)
+        .extract_if(.., |file_state| file_state.pair_key == pair_key)
+        .collect();
+    store_file_states(file_states).await
+}
+
+async fn write_all_file_states<B: RemoteBackend>(worker: &SyncWorker<B>) -> Result<()> {
+    let file_states = lock(&worker.file_states).drain(..).collect();
+    store_file_states(file_states).await
+}
+
+async fn store_file_states(file_states: Vec<PendingFileState>) -> Result<()> {
+    if file_states.is_empty() {
+        return Ok(());
+    }
+
+    let values: Vec<(String, Vec<u8>)> = file_states.into_iter().map(|file_state| (file_state.local_path, file_state.data)).collect();
+    db::blocking(move || db::write_many_bytes(STATE_TABLE, &values)).await
+}
+
+async fn load_file_state(local_path: &str) -> Result<Option<FileState>> {
+    match load_bytes(STATE_TABLE, local_path).await? {
+        Some(data) => Ok(postcard::from_bytes::<FileState>(&data).ok()),
+        None => Ok(None)
+    }
+}
+
+
+// CONTENT HASHES
+async fn get_local_file_hash(local_path: &str) -> Result<String> {
+    let metadata = get_local_file_info(local_path).await?;
+    let modified: DateTime<Utc> = metadata.modified()?.into();
+
+    let cached_hash = load_bytes(HASH_CACHE_TABLE, local_path).await?
+        .and_then(|data| postcard::from_bytes::<CachedHash>(&data).ok());
+
+    if let Some(cached_hash) = cached_hash
+        && cached_hash.size == metadata.len() && cached_hash.modified == modified {
+        return Ok(cached_hash.hash);
+    }
+
+    let hash = calculate_file_hash(local_path).await?;
+    let cached_hash = CachedHash {
+        size: metadata.len(),
+        modified: modified,
+        hash: hash.clone()
+    };
+    store_bytes(HASH_CACHE_TABLE, local_path, postcard::to_allocvec(&cached_hash)?).await?;
+
+    Ok(hash)
+}
+
+async fn calculate_file_hash(local_path: &str) -> Result<String> {
+    let mut file = File::open(local_path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("{}:{:x}", HASH_ALGORITHM, hasher.finalize()))
+}
+
+async fn get_remote_file_hash<B: RemoteBackend>(worker: &SyncWorker<B>, remote_path: &str, file_state: Option<&FileState>, remote_file: &RemoteFile) -> Result<Option<String>> {
+    if let Some(checksum) = worker.backend.checksum(remote_path).await? {
+        return Ok(Some(checksum));
+    }
+
+    if let Some(file_state) = file_state
+        && !is_remote_changed(file_state, remote_file) {
+        return Ok(file_state.hash.clone());
+    }
+
+    // Hash from metadata is valid only for the exact remote version it was saved with
+    let file_metadata = worker.file_metadata(remote_path)
+        .filter(|file_metadata| file_metadata.etag.is_some() && file_metadata.etag == remote_file.etag);
+
+    Ok(file_metadata.and_then(|file_metadata| file_metadata.hash))
+}
+
+
+// CHANGE POLLING
+async fn poll_changed_pairs<B: RemoteBackend>(backend: &B, pairs: &[(String, String)]) -> Result<Vec<String>> {
+    let mut changed_pairs = Vec::new();
+
+    for (local_path, remote_path) in pairs {
+        let saved_marker = load_bytes(CHANGE_MARKERS_TABLE, local_path).await?
+            .and_then(|data| postcard::from_bytes::<ChangeMarker>(&data).ok());
+        let known_tag = saved_marker.as_ref().and_then(|marker| marker.remote_tag.as_deref());
+        let marker = read_change_marker(backend, local_path, remote_path, known_tag).await?;
+
+        // A server without tags can't tell about changes, so such pairs are always compared
+        if marker.remote_tag.is_none() || saved_marker.as_ref() != Some(&marker) {
+            changed_pairs.push(local_path.clone());
+        }
+    }
+
+    Ok(changed_pairs)
+}
+
+async fn save_change_marker<B: RemoteBackend>(worker: &SyncWorker<B>, local_path: &str, remote_path: &str) {
+    // Without a marker the pair is compared again at the next poll
+    if write_change_marker(worker, local_path, remote_path).await.is_err() {
+        let local_path = local_path.to_owned();
+        let _ = db::blocking(move || db::delete_bytes(CHANGE_MARKERS_TABLE, &local_path)).await;
+    }
+}
+
+async fn write_change_marker<B: RemoteBackend>(worker: &SyncWorker<B>, local_path: &str, remote_path: &str) -> Result<()> {
+    let marker = read_change_marker(&worker.backend, local_path, remote_path, None).await?;
+    store_bytes(CHANGE_MARKERS_TABLE, local_path, postcard::to_allocvec(&marker)?).await?;
+
+    Ok(())
+}
+
+async fn read_change_marker<B: RemoteBackend>(backend: &B, local_path: &str, remote_path: &str, known_tag: Option<&str>) -> Result<ChangeMarker> {
+    Ok(ChangeMarker {
+        remote_tag: backend.tag(remote_path, known_tag).await?,
+        local_fingerprint: get_local_fingerprint(local_path).await?
+    })
+}
+
+// Sizes and modification times of everything inside the local path
+pub(crate) async fn get_local_fingerprint(local_path: &str) -> Result<String> {
+    let local_path = PathBuf::from(local_path);
+
+    tokio::task::spawn_blocking(move || {
+        let roots = local_tree_node(local_path)?.into_iter().collect();
+        backend::tree_fingerprint(roots, |dir_path| {
+            let mut children = Vec::new();
+            for dir_entry in std::fs::read_dir(dir_path)? {
+                let dir_entry = dir_entry?;
+                if !is_partial_download(&dir_entry.file_name().to_string_lossy()) {
+                    children.extend(local_tree_node(dir_entry.path())?);
+                }
+            }
+            Ok(children)
+        })
+    }).await?
+}
+
+// None for a path that is already gone
+fn local_tree_node(path: PathBuf) -> Result<Option<TreeNode>> {
+    let metadata = match std::fs::metadata(&path) {
+        Ok(metadata) => { metadata }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => { return Ok(None); }
+        Err(e) => { return Err(e.into()); }
+    };
+
+    Ok(Some(TreeNode {
+        path: path,
+        size: metadata.len(),
+        modified: metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos(),
+        is_dir: metadata.is_dir()
+    }))
+}
+
+
+// PAIR OPTIONS
+pub fn load_pair_options(local_path: &str) -> PairOptions {
+    db::read_bytes(PAIR_OPTIONS_TABLE, local_path)
+        .ok()
+        .flatten()
+        .and_then(|data| decode_pair_options(&data))
+        .unwrap_or_default()
+}
+
+fn decode_pair_options(data: &[u8]) -> Option<PairOptions> {
+    if let Ok((pair_options, [])) = postcard::take_from_bytes::<PairOptions>(data) {
+        return Some(pair_options);
+    }
+
+    let legacy = postcard::from_bytes::<LegacyPairOptions>(data).ok()?;
+    Some(PairOptions { max_deletions: legacy.max_deletions, ..PairOptions::default() })
+}
+
+pub fn save_pair_options(local_path: &str, pair_options: &PairOptions) -> Result<()> {
+    db::write_bytes(PAIR_OPTIONS_TABLE, local_path, &postcard::to_allocvec(pair_options)?)?;
+    Ok(())
+}
+
+// States of files and unfinished transfers of a pair, they are only valid for the server they were made with
+pub fn forget_sync_state(key: &str) -> Result<(), redb::Error> {
+    db::delete_under_path(STATE_TABLE, key)?;
+    db::delete_under_path(HASH_CACHE_TABLE, key)?;
+    db::delete_under_path(DOWNLOADS_TABLE, key)?;
+    db::delete_under_path(UPLOADS_TABLE, key)?;
+    db::delete_bytes(CHANGE_MARKERS_TABLE, key)
+}
+
+
+// FUNCTIONS FOR SAVING REMOTE FILES METADATA
+async fn save_and_upload_metadata<B: RemoteBackend>(backend: &B, syncmetadata: &SyncMetadata) -> Result<()> {
+    let data = postcard::to_allocvec(&syncmetadata)?;
+    backend.write(METADATA_FILENAME, &UploadSource::from_bytes(data)).await
+}
+
+async fn load_metadata<B: RemoteBackend>(backend: &B, retry: RetryPolicy) -> Result<SyncMetadata> {
+    let data = with_retry(retry, || async {
+        let mut remote_read = backend.read(METADATA_FILENAME, 0, None).await?;
+        let mut data = Vec::new();
+
+        while let Some(chunk) = remote_read.stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }).await?;
+
+    decode_metadata(&data)
+}
+
+fn decode_metadata(data: &[u8]) -> Result<SyncMetadata> {
+    if let Ok((syncmetadata, [])) = postcard::take_from_bytes::<SyncMetadata>(data) {
+        return Ok(syncmetadata);
+    }
+
+    let legacy = postcard::from_bytes::<LegacySyncMetadata>(data)?;
+    let files = legacy.files
+        .into_iter()
+        .map(|(remote_path, modified)| (remote_path, FileMetadata { modified: modified, hash: None, etag: None }))
+        .collect();
+
+    Ok(SyncMetadata { files: files })
+}
+
+
+// DOWNLOAD AND UPLOAD FILES
+async fn download_with_progress<B: RemoteBackend>(worker: &SyncWorker<B>, pair_key: &str, local_path: &str, remote_path: &str) -> Result<()> {
+    let remote_file = get_remote_file_info(worker, remote_path).await?;
+    let modified = get_remote_modified_time(worker, remote_path).await?;
+
+    let tracker = worker.track_transfer(pair_key, local_path, remote_file.size, TransferDirection::Download);
+    // Every attempt picks up the partial file left by the previous one
+    let result = with_retry(worker.retry, || download_file(&worker.backend, local_path, remote_path, modified, &remote_file, &tracker)).await;
+    worker.finish_transfer(&tracker).await?;
+    result
+}
+
+async fn upload_with_progress<B: RemoteBackend>(worker: &SyncWorker<B>, pair_key: &str, local_path: &str, remote_path: &str, checksum: &str) -> Result<()> {
+    let source = UploadSource::from_file(local_path, Some(checksum.to_owned())).await?;
+    let tracker = worker.track_transfer(pair_key, local_path, source.size, TransferDirection::Upload);
+    let result = worker.backend.write(remote_path, &source.with_tracker(&tracker)).await;
+    worker.finish_transfer(&tracker).await?;
+    result?;
+
+    refresh_remote_entry(worker, remote_path).await?;
+    Ok(())
+}
+
+async fn download_file<B: RemoteBackend>(backend: &B, local_path: &str, remote_path: &str, modified: DateTime<Utc>, remote_file: &RemoteFile, tracker: &ProgressTracker) -> Result<()> {
+    let partial_path = partial_download_path(local_path);
+    let etag = remote_file.etag.clone();
+    let resume_from = resumable_download_size(local_path, remote_path, remote_file, &partial_path).await?;
+
+    let remote_read = backend.read(remote_path, resume_from, etag.as_deref()).await?;
+
+    if let Some(etag) = &etag {
+        let partial_download = PartialDownload {
+            remote_path: remote_path.to_owned(),
+            etag: etag.clone()
+        };
+        store_bytes(DOWNLOADS_TABLE, local_path, postcard::to_allocvec(&partial_download)?).await?;
+    }
+
+    let append = remote_read.offset > 0;
+    tracker.rewind(remote_read.offset);
+
+    let _guard = PartialDownloadGuard {
+        partial_path: partial_path.clone(),
+        keep: etag.is_some()
+    };
+
+    write_partial_download(remote_read, &partial_path, modified, append, remote_file.size, tracker).await?;
+
+    fs::rename(&partial_path, local_path).await?;
+    let local_path = local_path.to_owned();
+    db::blocking(move || db::delete_bytes(DOWNLOADS_TABLE, &local_path)).await?;
+    Ok(())
+}
+
+async fn write_partial_download(remote_read: RemoteRead, partial_path: &Path, modified: DateTime<Utc>, append: bool, expected_size: u64, tracker: &ProgressTracker) -> Result<()> {
+    let expected_length = remote_read.length;
+    let mut file = if append {
+        OpenOptions::new().append(true).open(partial_path).await?
+    } else {
+        File::create(partial_path).await?
+    };
+    let mut stream = remote_read.stream;
+    let mut written: u64 = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+
+        tracker.advance(chunk.len() as u64);
+        tracker.pace(chunk.len() as u64).await;
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+
+    if let Some(expected_length) = expected_length
+        && written != expected_length {
+        return Err(anyhow!("Downloaded {} bytes instead of {} into {}", written, expected_length, partial_path.display()));
+    }
+
+    let size = file.metadata().await?.len();
+
+    if size != expected_size {
+        // Content does not add up to the remote file, the next attempt starts from scratch
+        file.set_len(0).await?;
+        return Err(anyhow!("Downloaded file {} has {} bytes instead of {}", partial_path.display(), size, expected_size));
+    }
+
+    file.into_std().await.set_modified(modified.into())?;
+
+    Ok(())
+}
+
+async fn resumable_download_size(local_path: &str, remote_path: &str, remote_file: &RemoteFile, partial_path: &Path) -> Result<u64> {
+    let Some(etag) = &remote_file.etag else {
+        return Ok(0);
+    };
+
+    let partial_download = load_bytes(DOWNLOADS_TABLE, local_path).await?
+        .and_then(|data| postcard::from_bytes::<PartialDownload>(&data).ok());
+
+    match partial_download {
+        Some(partial_download) if partial_download.remote_path == remote_path && partial_download.etag == *etag => {
+            let size = fs::metadata(partial_path).await.map(|metadata| metadata.len()).unwrap_or(0);
+            Ok(if size < remote_file.size { size } else { 0 })
+        }
+        _ => Ok(0)
+    }
+}
+
+async fn is_resumable_download(local_path: &str) -> Result<bool> {
+    Ok(load_bytes(DOWNLOADS_TABLE, local_path).await?.is_some())
+}
+
+fn partial_download_target(partial_path: &Path) -> String {
+    let file_name = partial_path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let target_name = file_name
+        .trim_start_matches('.')
+        .trim_end_matches(PARTIAL_DOWNLOAD_SUFFIX);
+
+    partial_path.with_file_name(target_name).to_string_lossy().into_owned()
+}
+
+fn partial_download_path(local_path: &str) -> PathBuf {
+    let path = Path::new(local_path);
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}{}", file_name, PARTIAL_DOWNLOAD_SUFFIX))
+}
+
+pub fn is_partial_download(file_name: &str) -> bool {
+    file_name.starts_with('.') && file_name.ends_with(PARTIAL_DOWNLOAD_SUFFIX)
+}
+
+async fn remove_stale_download(local_path: &str) -> Result<()> {
+    let partial_path = partial_download_path(local_path);
+
+    if partial_path.exists() && !is_resumable_download(local_path).await? {
+        fs::remove_file(partial_path).await?;
+    }
+
+    Ok(())
+}
+
+
+// OTHER USEFUL FUNCTIONS
+async fn is_local_file_exist(filepath: &str) -> bool {
+    Path::new(filepath).exists()
+}
+
+async fn is_remote_file_exist<B: RemoteBackend>(worker: &SyncWorker<B>, filepath: &str) -> Result<bool> {
+    Ok(get_remote_entry(worker, filepath).await?.is_some())
+}
+
+async fn get_remote_file_info<B: RemoteBackend>(worker: &SyncWorker<B>, filepath: &str) -> Result<RemoteFile> {
+    if let Some(RemoteEntry::File(remote_file)) = get_remote_entry(worker, filepath).await? {
+        Ok(remote_file)
+    } else {
+        Err(anyhow!("Remote file {} not found", filepath))
+    }
+}
+
+async fn get_local_file_info(filepath: &str) -> Result<Metadata> {
+    Ok(fs::metadata(Path::new(filepath)).await?)
+}
+
+async fn is_download_possible(local_path: &str) -> bool {
+    Path::new(local_path).parent().is_some_and(|path| {
+        path.exists()
+    })
+}
+
+async fn get_remote_modified_time<B: RemoteBackend>(worker: &SyncWorker<B>, remote_path: &str) -> Result<DateTime<Utc>> {
+    if let Some(file_metadata) = worker.file_metadata(remote_path) {
+        return Ok(file_metadata.modified);
+    }
+
+    Ok(get_exact_remote_file_info(worker, remote_path).await?.modified)
+}
+
+// Times from listings may be approximate, then the file is looked up by itself
+async fn get_exact_remote_file_info<B: RemoteBackend>(worker: &SyncWorker<B>, remote_path: &str) -> Result<RemoteFile> {
+    if worker.backend.lists_modified_times() {
+        return get_remote_file_info(worker, remote_path).await;
+    }
+
+    match refresh_remote_entry(worker, remote_path).await? {
+        Some(RemoteEntry::File(remote_file)) => { Ok(remote_file) }
+        _ => { Err(anyhow!("Remote file {} not found", remote_path)) }
+    }
+}
+
+fn compare_modified_time<B: RemoteBackend>(worker: &SyncWorker<B>, remote_path: &str, metadata: &Metadata, remote_file: &RemoteFile) -> Result<Ordering> {
+    let metadata_dt: DateTime<Utc> = metadata.modified()?.into();
+
+    if let Some(file_metadata) = worker.file_metadata(remote_path) {
+        return Ok(metadata_dt.cmp(&file_metadata.modified));
+    }
+
+    Ok(metadata_dt.cmp(&remote_file.modified))
+}
\ No newline at end of file
@@ -0,0 +1,38 @@
+// Synchronization engine, the GUI, the CLI and the daemon are built on top of it
+pub mod backend;
+pub mod db;
+pub mod engine;
+pub mod local;
+pub mod profiles;
+pub mod s3;
+pub mod sftp;
+pub mod webdav;
+mod retry;
+
+pub use backend::RemoteBackend;
+pub use engine::{Conflict, ConflictPolicy, ConflictResolution, SyncEngine, SyncHandle, SyncPair, SyncPurpose, SyncSettings, TransferProgress};
+pub use profiles::ServerProfile;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub enum SyncState {
+    Synchronized,
+    UnsynchronizedRemote,
+    UnsynchronizedLocal,
+    Conflict,
+    CantSynchronize,
+    Cancelled
+}
+
+#[derive(Debug, Clone)]
+pub enum SyncEvent {
+    // Lets the consumer resolve conflicts and cancel the run
+    Started(SyncHandle),
+    PairState(String, SyncState),
+    // Pair key, local path and progress of one transfer
+    TransferProgress(String, String, TransferProgress),
+    TransferFinished(String),
+    Progress(TransferProgress),
+    Conflict(Conflict),
+    Error(String),
+    Finished
+}
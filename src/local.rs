@@ -0,0 +1,197 @@
+use std::{fs::Metadata, io::{ErrorKind, SeekFrom}, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use tokio::{fs::{self, File}, io::{AsyncSeekExt, AsyncWriteExt}};
+use tokio_util::io::ReaderStream;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use anyhow::{Result, anyhow};
+
+use crate::{backend::{RemoteBackend, RemoteEntry, RemoteFile, RemoteRead, UploadSource, upload_temp_path, walk_directory}, engine};
+
+const LOCAL_SCHEME: &str = "file://";
+
+// The "remote" side is another directory, e.g. a mounted NAS or a USB drive
+pub struct LocalBackend {
+    root: PathBuf
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf) -> Self {
+        LocalBackend { root: root }
+    }
+
+    // Hosts like file:///mnt/nas pick this backend
+    pub fn from_host(host: &str) -> Option<Self> {
+        host.strip_prefix(LOCAL_SCHEME).map(|root| LocalBackend::new(PathBuf::from(root)))
+    }
+
+    fn full_path(self: &Self, path: &str) -> PathBuf {
+        self.root.join(path.trim_matches('/'))
+    }
+}
+
+impl RemoteBackend for LocalBackend {
+    async fn check_connection(self: &Self) -> bool {
+        self.root.is_dir()
+    }
+
+    async fn list(self: &Self, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+        list_directory(self, dir_path, recursive).await
+    }
+
+    async fn stat(self: &Self, path: &str) -> Result<Option<RemoteEntry>> {
+        match fs::metadata(self.full_path(path)).await {
+            Ok(metadata) => Ok(Some(to_remote_entry(&metadata)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    async fn read(self: &Self, path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead> {
+        read_file(self, path, offset, etag).await
+    }
+
+    async fn write(self: &Self, path: &str, source: &UploadSource) -> Result<()> {
+        write_file(self, path, source).await
+    }
+
+    async fn mkdir(self: &Self, dir_path: &str) -> Result<()> {
+        fs::create_dir_all(self.full_path(dir_path)).await?;
+        Ok(())
+    }
+
+    async fn delete(self: &Self, path: &str) -> Result<()> {
+        let full_path = self.full_path(path);
+
+        // Directories are deleted only when they are empty, like on the other side
+        let result = match fs::metadata(&full_path).await {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir(&full_path).await,
+            Ok(_) => fs::remove_file(&full_path).await,
+            Err(e) => Err(e)
+        };
+
+        match result {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(())
+        }
+    }
+
+    async fn rename(self: &Self, from_path: &str, to_path: &str) -> Result<()> {
+        let to_path = self.full_path(to_path);
+
+        if let Some(parent) = to_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(self.full_path(from_path), to_path).await?;
+        Ok(())
+    }
+
+    async fn set_modified(self: &Self, path: &str, modified: DateTime<Utc>) -> Result<bool> {
+        let file = File::options().write(true).open(self.full_path(path)).await?;
+        file.into_std().await.set_modified(modified.into())?;
+        Ok(true)
+    }
+
+    async fn tag(self: &Self, path: &str, _known_tag: Option<&str>) -> Result<Option<String>> {
+        let full_path = self.full_path(path);
+
+        if !full_path.exists() {
+            return Ok(Some(String::new()));
+        }
+
+        Ok(Some(engine::get_local_fingerprint(&full_path.to_string_lossy()).await?))
+    }
+}
+
+async fn list_directory(backend: &LocalBackend, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+    let root = backend.root.clone();
+    let dir_path = dir_path.to_owned();
+
+    tokio::task::spawn_blocking(move || walk_directory(&dir_path, recursive, |dir_path| {
+        let full_path = root.join(dir_path.trim_matches('/'));
+        if !full_path.is_dir() {
+            return Ok(None);
+        }
+
+        let mut listing = Vec::new();
+        for dir_entry in std::fs::read_dir(full_path)? {
+            let dir_entry = dir_entry?;
+            let Some(name) = dir_entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            listing.push((name, to_remote_entry(&std::fs::metadata(dir_entry.path())?)?));
+        }
+        Ok(Some(listing))
+    })).await?
+}
+
+async fn read_file(backend: &LocalBackend, path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead> {
+    let mut file = File::open(backend.full_path(path)).await?;
+    let metadata = file.metadata().await?;
+
+    // A file that changed since the partial download is sent from the start
+    let offset = if offset > 0 && etag.is_some() && etag == file_etag(&metadata).as_deref() {
+        offset.min(metadata.len())
+    } else {
+        0
+    };
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    Ok(RemoteRead {
+        offset: offset,
+        length: Some(metadata.len() - offset),
+        stream: ReaderStream::new(file).map_err(anyhow::Error::from).boxed()
+    })
+}
+
+async fn write_file(backend: &LocalBackend, path: &str, source: &UploadSource) -> Result<()> {
+    let full_path = backend.full_path(path);
+    let Some(parent) = full_path.parent() else {
+        return Err(anyhow!("Can't write {}", full_path.display()));
+    };
+    fs::create_dir_all(parent).await?;
+
+    // Readers of the directory never see a half-written file
+    let temp_path = upload_temp_path(&full_path);
+
+    if let Err(e) = write_temp_file(&temp_path, source).await {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+
+    fs::rename(&temp_path, &full_path).await?;
+    Ok(())
+}
+
+async fn write_temp_file(temp_path: &Path, source: &UploadSource) -> Result<()> {
+    let mut file = File::create(temp_path).await?;
+    let mut stream = source.open(0, source.size).await?;
+
+    while let Some(chunk) = stream.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+
+    file.into_std().await.set_modified(source.modified.into())?;
+    Ok(())
+}
+
+
+fn to_remote_entry(metadata: &Metadata) -> Result<RemoteEntry> {
+    if metadata.is_dir() {
+        return Ok(RemoteEntry::Directory);
+    }
+
+    Ok(RemoteEntry::File(RemoteFile {
+        size: metadata.len(),
+        modified: metadata.modified()?.into(),
+        etag: file_etag(metadata)
+    }))
+}
+
+// Size and modification time stand in for an ETag, they change with every write
+fn file_etag(metadata: &Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("{:x}-{:x}", modified.as_nanos(), metadata.len()))
+}
@@ -1,46 +1,271 @@
 #![windows_subsystem = "windows"]
 
-mod webdav;
-mod db;
+mod watcher;
+mod scheduler;
+mod cli;
+mod daemon;
 
-use std::{collections::{HashMap, VecDeque}, path::Path, sync::Arc};
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque}, path::Path, str::FromStr, sync::Arc, time::Duration};
 
+use chrono::{DateTime, Local, NaiveTime, Utc};
 use iced::{
     Element, Fill, Subscription, Task, stream,
-    widget::{button, column, row, rule, scrollable, text, text_input}
+    futures::{StreamExt, channel::mpsc},
+    widget::{button, column, pick_list, progress_bar, row, rule, scrollable, text, text_input}
 };
 use tokio::runtime::Runtime;
 use typed_path::UnixPath;
-use bimap::BiHashMap;
 
-use crate::{db::{AUTH_TABLE, PAIRS_TABLE}, webdav::SyncPurpose};
+use filesync_rust::{Conflict, ConflictPolicy, ConflictResolution, SyncEngine, SyncEvent, SyncHandle, SyncPair, SyncPurpose, SyncSettings, SyncState, TransferProgress, ServerProfile, db::{self, PAIRS_TABLE, PAIR_OPTIONS_TABLE, PAIR_PROFILES_TABLE, SETTINGS_TABLE}, engine::{self, PairOptions}, profiles::{self, DEFAULT_PROFILE}};
+
+use crate::{daemon::{DaemonRequest, DaemonStatus}, scheduler::Schedule};
 
 fn main() -> iced::Result {
+    if let Some(path) = std::env::var_os("FILESYNC_DB") {
+        let _ = db::set_path(path);
+    }
+
+    // Any arguments mean a headless run from the command line
+    if std::env::args_os().len() > 1 {
+        attach_console();
+        std::process::exit(cli::run());
+    }
+
     iced::application(AppState::new, AppState::update, AppState::view)
     .title("filesync")
     .subscription(AppState::subscription)
     .run()
 }
 
+// The window subsystem starts without a console on Windows, so commands print into the one they were started from
+#[cfg(windows)]
+fn attach_console() {
+    use windows_sys::Win32::System::Console::{ATTACH_PARENT_PROCESS, AttachConsole};
+
+    // Fails when started outside of a console, then there is nowhere to print
+    unsafe { AttachConsole(ATTACH_PARENT_PROCESS); }
+}
+
+#[cfg(not(windows))]
+fn attach_console() {}
+
 fn is_valid_unix_path(path: &str) -> bool {
     UnixPath::new(path).is_valid()
 }
 
+// Checks paths of a new pair and makes them absolute
+fn normalize_pair(local_path: &str, remote_path: &str) -> Result<(String, String), String> {
+    if local_path.is_empty() || remote_path.is_empty() {
+        return Err(String::from("Empty path"));
+    }
+
+    if !Path::new(local_path).exists() {
+        return Err(String::from("System path not found"));
+    }
+
+    if !is_valid_unix_path(remote_path) {
+        return Err(String::from("Server path is invalid"));
+    }
+
+    let local_path = match typed_path::NativePath::new(local_path).absolutize() {
+        Ok(path) => { path.to_string() }
+        Err(e) => { return Err(format!("Can't absolutize local path {}", e)); }
+    };
+    let remote_path = match UnixPath::new(&format!("/{}", remote_path)).absolutize() {
+        Ok(path) => { path.to_string() }
+        Err(e) => { return Err(format!("Can't absolutize remote path {}", e)); }
+    };
+
+    Ok((local_path, remote_path))
+}
+
+// Saved pairs with the profiles they use
+fn load_sync_pairs() -> Vec<SyncPair> {
+    db::read_as_map(PAIRS_TABLE)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(local_path, remote_path)| SyncPair {
+            profile: profiles::load_pair_profile(&local_path),
+            local_path: local_path,
+            remote_path: remote_path
+        })
+        .collect()
+}
+
+// Everything remembered about a pair besides the pair itself
+fn forget_pair_state(key: &str) -> Result<(), redb::Error> {
+    engine::forget_sync_state(key)
+        .and_then(|_| db::delete_bytes(PAIR_OPTIONS_TABLE, key))
+        .and_then(|_| db::delete(PAIR_PROFILES_TABLE, key))
+}
+
+// New pairs use the default profile while it exists
+fn default_profile_name(profiles: &BTreeMap<String, ServerProfile>) -> String {
+    if profiles.contains_key(DEFAULT_PROFILE) {
+        return DEFAULT_PROFILE.to_owned();
+    }
+
+    profiles.keys().next().cloned().unwrap_or_else(|| DEFAULT_PROFILE.to_owned())
+}
+
+fn load_sync_settings() -> SyncSettings {
+    // Settings are read one by one, different settings may share a value
+    SyncSettings {
+        max_transfers: read_setting("max_transfers").unwrap_or(engine::DEFAULT_MAX_TRANSFERS),
+        max_attempts: read_setting("max_attempts").unwrap_or(engine::DEFAULT_MAX_ATTEMPTS),
+        upload_limit: read_setting("upload_limit"),
+        download_limit: read_setting("download_limit"),
+        unlimited_hours: match (
+            read_setting::<String>("unlimited_from").and_then(|time| parse_optional_time(&time).ok().flatten()),
+            read_setting::<String>("unlimited_to").and_then(|time| parse_optional_time(&time).ok().flatten())
+        ) {
+            (Some(from), Some(to)) => Some((from, to)),
+            _ => None
+        }
+    }
+}
+
+fn load_schedule() -> Schedule {
+    let minutes = |key: &str| read_setting::<u64>(key).filter(|minutes| *minutes > 0).map(|minutes| Duration::from_secs(minutes * 60));
+
+    Schedule {
+        sync_interval: minutes("sync_interval"),
+        poll_interval: minutes("poll_interval"),
+        purpose: match read_setting::<String>("scheduled_purpose").as_deref() {
+            Some("synchronize") => SyncPurpose::Synchronize,
+            _ => SyncPurpose::Check
+        }
+    }
+}
+
+fn format_datetime(datetime: &DateTime<Utc>) -> String {
+    datetime.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn read_setting<T: FromStr>(key: &str) -> Option<T> {
+    db::read(SETTINGS_TABLE, key).ok().flatten().and_then(|value| value.parse().ok())
+}
+
+fn write_setting(key: &str, value: Option<String>) -> Result<(), redb::Error> {
+    match value {
+        Some(value) => db::write(SETTINGS_TABLE, key, &value),
+        None => db::delete(SETTINGS_TABLE, key)
+    }
+}
+
+fn parse_optional<T: FromStr>(input: &str) -> Result<Option<T>, T::Err> {
+    match input.trim() {
+        "" => Ok(None),
+        input => input.parse().map(Some)
+    }
+}
+
+fn parse_optional_time(input: &str) -> Result<Option<NaiveTime>, chrono::ParseError> {
+    match input.trim() {
+        "" => Ok(None),
+        input => NaiveTime::parse_from_str(input, "%H:%M").map(Some)
+    }
+}
+
+fn format_minutes(interval: Option<Duration>) -> String {
+    interval.map(|interval| (interval.as_secs() / 60).to_string()).unwrap_or_default()
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_progress(progress: &TransferProgress) -> String {
+    let mut description = format!(
+        "{} of {}, {}/s",
+        format_bytes(progress.transferred),
+        format_bytes(progress.total),
+        format_bytes(progress.bytes_per_second)
+    );
+
+    if let Some(eta) = progress.eta {
+        description.push_str(&format!(", {} s left", eta.as_secs()));
+    }
+
+    description
+}
+
+fn progress_fraction(progress: &TransferProgress) -> f32 {
+    if progress.total == 0 {
+        1.0
+    } else {
+        (progress.transferred as f64 / progress.total as f64).min(1.0) as f32
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct AppState {
     // Flags
     pub sync_purpose: Option<SyncPurpose>,
+    pub cancelling: bool,
     pub authorization: bool,
+    pub settings: bool,
+    pub watching: bool,
     // Text inputs
+    pub profile_name_input: String,
     pub host: String,
     pub login: String,
     pub password: String,
+    pub private_key: String,
+    pub host_key: String,
     pub local_path_input: String,
     pub remote_path_input: String,
+    pub pair_profile_input: String,
+    pub max_deletions_input: String,
+    pub max_transfers_input: String,
+    pub max_attempts_input: String,
+    pub upload_limit_input: String,
+    pub download_limit_input: String,
+    pub unlimited_from_input: String,
+    pub unlimited_to_input: String,
+    pub pair_upload_limit_input: String,
+    pub pair_download_limit_input: String,
+    pub sync_interval_input: String,
+    pub poll_interval_input: String,
+    pub scheduled_synchronize_input: bool,
+    // Settings
+    pub sync_settings: SyncSettings,
+    pub schedule: Schedule,
+    pub profiles: BTreeMap<String, ServerProfile>,
     // Synchronization pairs
-    pub pairs: BiHashMap<String, String>,
+    pub pairs: BTreeMap<String, String>,
+    pub pair_profiles: HashMap<String, String>,
     pub pairs_syncstate: HashMap<String, SyncState>,
+    // Transfers of the running synchronization by local path
+    pub transfers: BTreeMap<String, (String, TransferProgress)>,
+    pub sync_progress: Option<TransferProgress>,
+    // Pairs of the running synchronization, all pairs if not set
+    pub sync_scope: Option<BTreeSet<String>>,
+    pub sync_run: u64,
+    // Pairs changed locally while watching, waiting for the next synchronization
+    pub pending_changes: BTreeSet<String>,
     pub editing: Option<EditingState>,
+    // Conflicts
+    pub conflicts: VecDeque<Conflict>,
+    // Conflicts of the daemon left for later in this window
+    pub deferred_conflicts: HashSet<String>,
+    pub sync_handle: Option<SyncHandle>,
+    // Daemon, if it is running
+    pub daemon: Option<DaemonStatus>,
+    pub daemon_errors_seen: u64,
     // Error messages
     pub error_msgs: VecDeque<String>,
 }
@@ -52,22 +277,30 @@ pub enum EditingState {
     Delete { key: String, value: String },
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum SyncState {
-    Synchronized,
-    UnsynchronizedRemote,
-    UnsynchronizedLocal,
-    CantSynchronize
-}
-
 #[derive(Debug, Clone)]
 pub enum Message {
     // Text inputs
+    ProfileNameInputChanged(String),
     HostInputChanged(String),
     LoginInputChanged(String),
     PasswordInputChanged(String),
+    PrivateKeyInputChanged(String),
+    HostKeyInputChanged(String),
     LocalPathInputChanged(String),
     RemotePathInputChanged(String),
+    PairProfileSelected(String),
+    MaxDeletionsInputChanged(String),
+    MaxTransfersInputChanged(String),
+    MaxAttemptsInputChanged(String),
+    UploadLimitInputChanged(String),
+    DownloadLimitInputChanged(String),
+    UnlimitedFromInputChanged(String),
+    UnlimitedToInputChanged(String),
+    PairUploadLimitInputChanged(String),
+    PairDownloadLimitInputChanged(String),
+    SyncIntervalInputChanged(String),
+    PollIntervalInputChanged(String),
+    ScheduledPurposeToggled,
     // Editing
     CreatePair,
     EditPair(String),
@@ -77,42 +310,132 @@ pub enum Message {
     // Synchronization events
     Synchronize,
     SynchronizeCheck,
+    CancelSynchronize,
     StopSynchronize,
+    SyncStarted(SyncHandle),
     UpdatePairSyncState(String, SyncState),
+    TransferProgress(String, String, TransferProgress),
+    TransferFinished(String),
+    SyncProgress(TransferProgress),
+    // Watching
+    ToggleWatch,
+    LocalChanges(Vec<String>),
+    // Schedule, changed pairs or all pairs if not set
+    ScheduledSync(Option<Vec<String>>),
+    // Daemon
+    DaemonStatusChanged(Option<DaemonStatus>),
+    SendDaemonRequest(DaemonRequest),
+    // Conflicts
+    ConflictDetected(Conflict),
+    ResolveConflict(String, ConflictResolution),
     // Auth
     OpenAuth,
+    ProfileSelected(String),
     SaveAuth,
+    DeleteProfile,
+    // Settings
+    OpenSettings,
+    SaveSettings,
     // Error messages
     ShowError(String),
     CloseError
 }
 
+impl From<SyncEvent> for Message {
+    fn from(event: SyncEvent) -> Self {
+        match event {
+            SyncEvent::Started(sync_handle) => Message::SyncStarted(sync_handle),
+            SyncEvent::PairState(key, syncstate) => Message::UpdatePairSyncState(key, syncstate),
+            SyncEvent::TransferProgress(pair_key, local_path, progress) => Message::TransferProgress(pair_key, local_path, progress),
+            SyncEvent::TransferFinished(local_path) => Message::TransferFinished(local_path),
+            SyncEvent::Progress(progress) => Message::SyncProgress(progress),
+            SyncEvent::Conflict(conflict) => Message::ConflictDetected(conflict),
+            SyncEvent::Error(e) => Message::ShowError(e),
+            SyncEvent::Finished => Message::StopSynchronize
+        }
+    }
+}
+
 impl AppState {
     fn new() -> AppState {
-        let pairs_table = db::read_as_hashmap(PAIRS_TABLE).unwrap_or_default();
-        let auth_table = db::read_as_hashmap(AUTH_TABLE).unwrap_or_default();
+        // A running daemon owns the database, the window only shows its state and passes commands
+        if let Ok(status) = daemon::request(DaemonRequest::Status) {
+            let mut state = AppState::default();
+            state.update_daemon_status(Some(status));
+            return state;
+        }
+
+        let database_error = db::open().err().map(|e| format!("Can't open the database, a command may be using it: {}", e));
+        let pairs_table = db::read_as_map(PAIRS_TABLE).unwrap_or_default();
+        let pair_profiles = pairs_table.keys().map(|key| (key.clone(), profiles::load_pair_profile(key))).collect();
+        let profiles = profiles::load_profiles();
+        let profile_name = default_profile_name(&profiles);
+        let profile = profiles.get(&profile_name).cloned().unwrap_or_default();
+        let sync_settings = load_sync_settings();
+
+        let schedule = load_schedule();
 
         AppState {
             // Flags
             sync_purpose: Some(SyncPurpose::Check),
+            cancelling: false,
             authorization: false,
+            settings: false,
+            watching: read_setting("watch").unwrap_or(false),
             // Text inputs
-            host: auth_table.get_by_left("host").unwrap_or(&"".to_string()).to_owned(),
-            login: auth_table.get_by_left("login").unwrap_or(&"".to_string()).to_owned(),
-            password: auth_table.get_by_left("password").unwrap_or(&"".to_string()).to_owned(),
+            profile_name_input: profile_name.clone(),
+            host: profile.host,
+            login: profile.login,
+            password: profile.password,
+            private_key: profile.private_key,
+            host_key: profile.host_key,
             local_path_input: String::new(),
             remote_path_input: String::new(),
+            pair_profile_input: profile_name,
+            max_deletions_input: String::new(),
+            max_transfers_input: sync_settings.max_transfers.to_string(),
+            max_attempts_input: sync_settings.max_attempts.to_string(),
+            upload_limit_input: sync_settings.upload_limit.map(|limit| limit.to_string()).unwrap_or_default(),
+            download_limit_input: sync_settings.download_limit.map(|limit| limit.to_string()).unwrap_or_default(),
+            unlimited_from_input: read_setting("unlimited_from").unwrap_or_default(),
+            unlimited_to_input: read_setting("unlimited_to").unwrap_or_default(),
+            pair_upload_limit_input: String::new(),
+            pair_download_limit_input: String::new(),
+            sync_interval_input: format_minutes(schedule.sync_interval),
+            poll_interval_input: format_minutes(schedule.poll_interval),
+            scheduled_synchronize_input: matches!(schedule.purpose, SyncPurpose::Synchronize),
+            // Settings
+            sync_settings: sync_settings,
+            schedule: schedule,
+            profiles: profiles,
             // Synchronization pairs
             pairs: pairs_table,
+            pair_profiles: pair_profiles,
             pairs_syncstate: HashMap::new(),
+            transfers: BTreeMap::new(),
+            sync_progress: None,
+            sync_scope: None,
+            sync_run: 0,
+            pending_changes: BTreeSet::new(),
             editing: None,
+            // Conflicts
+            conflicts: VecDeque::new(),
+            deferred_conflicts: HashSet::new(),
+            sync_handle: None,
+            // Daemon
+            daemon: None,
+            daemon_errors_seen: 0,
             // Error messages
-            error_msgs: VecDeque::new(),
+            error_msgs: database_error.into_iter().collect(),
         }
     }
 
     fn update(self: &mut Self, message: Message) -> Task<Message> {
         match message {
+            Message::ProfileNameInputChanged(profile_name) => {
+                self.profile_name_input = profile_name;
+                Task::none()
+            }
             Message::HostInputChanged(host) => {
                 self.host = host;
                 Task::none()
@@ -125,6 +448,14 @@ impl AppState {
                 self.password = password;
                 Task::none()
             }
+            Message::PrivateKeyInputChanged(private_key) => {
+                self.private_key = private_key;
+                Task::none()
+            }
+            Message::HostKeyInputChanged(host_key) => {
+                self.host_key = host_key;
+                Task::none()
+            }
             Message::LocalPathInputChanged(input) => {
                 self.local_path_input = input;
                 Task::none()
@@ -133,6 +464,58 @@ impl AppState {
                 self.remote_path_input = input;
                 Task::none()
             }
+            Message::PairProfileSelected(profile_name) => {
+                self.pair_profile_input = profile_name;
+                Task::none()
+            }
+            Message::MaxDeletionsInputChanged(input) => {
+                self.max_deletions_input = input;
+                Task::none()
+            }
+            Message::MaxTransfersInputChanged(input) => {
+                self.max_transfers_input = input;
+                Task::none()
+            }
+            Message::MaxAttemptsInputChanged(input) => {
+                self.max_attempts_input = input;
+                Task::none()
+            }
+            Message::UploadLimitInputChanged(input) => {
+                self.upload_limit_input = input;
+                Task::none()
+            }
+            Message::DownloadLimitInputChanged(input) => {
+                self.download_limit_input = input;
+                Task::none()
+            }
+            Message::UnlimitedFromInputChanged(input) => {
+                self.unlimited_from_input = input;
+                Task::none()
+            }
+            Message::UnlimitedToInputChanged(input) => {
+                self.unlimited_to_input = input;
+                Task::none()
+            }
+            Message::PairUploadLimitInputChanged(input) => {
+                self.pair_upload_limit_input = input;
+                Task::none()
+            }
+            Message::PairDownloadLimitInputChanged(input) => {
+                self.pair_download_limit_input = input;
+                Task::none()
+            }
+            Message::SyncIntervalInputChanged(input) => {
+                self.sync_interval_input = input;
+                Task::none()
+            }
+            Message::PollIntervalInputChanged(input) => {
+                self.poll_interval_input = input;
+                Task::none()
+            }
+            Message::ScheduledPurposeToggled => {
+                self.scheduled_synchronize_input = !self.scheduled_synchronize_input;
+                Task::none()
+            }
             Message::CreatePair => {
                 if self.editing.is_some() {
                     self.decline_editing();
@@ -146,9 +529,20 @@ impl AppState {
                     self.decline_editing();
                 }
 
-                if let Some((key, value)) = self.pairs.remove_by_left(&key) {
+                if let Some(value) = self.pairs.remove(&key) {
                     self.local_path_input = key.clone();
                     self.remote_path_input = value.clone();
+                    self.pair_profile_input = self.pair_profile(&key).to_owned();
+                    let pair_options = engine::load_pair_options(&key);
+                    self.max_deletions_input = pair_options.max_deletions
+                        .map(|max_deletions| max_deletions.to_string())
+                        .unwrap_or_default();
+                    self.pair_upload_limit_input = pair_options.upload_limit
+                        .map(|limit| limit.to_string())
+                        .unwrap_or_default();
+                    self.pair_download_limit_input = pair_options.download_limit
+                        .map(|limit| limit.to_string())
+                        .unwrap_or_default();
                     self.editing = Some(EditingState::Edit {
                         key: key,
                         value: value,
@@ -161,7 +555,7 @@ impl AppState {
                     self.decline_editing();
                 }
 
-                if let Some((key, value)) = self.pairs.remove_by_left(&key) {
+                if let Some(value) = self.pairs.remove(&key) {
                     self.editing = Some(EditingState::Delete {
                         key: key,
                         value: value,
@@ -172,46 +566,82 @@ impl AppState {
             Message::AcceptEditing => {
                 match &self.editing {
                     Some(EditingState::Create | EditingState::Edit { .. }) => {
-                        if self.local_path_input.is_empty() || self.remote_path_input.is_empty() {
-                            self.push_error_msg("Empty path");
-                            return Task::none();
-                        }
+                        let (local_path, remote_path) = match normalize_pair(&self.local_path_input, &self.remote_path_input) {
+                            Ok(pair) => { pair }
+                            Err(e) => {
+                                self.push_error_msg(&e);
+                                return Task::none();
+                            }
+                        };
+                        self.local_path_input = local_path;
+                        self.remote_path_input = remote_path;
 
-                        if !Path::new(&self.local_path_input).exists() {
-                            self.push_error_msg("System path not found");
+                        if self.pairs.contains_key(&self.local_path_input) {
+                            self.push_error_msg("This system path already in use");
                             return Task::none();
                         }
 
-                        if !is_valid_unix_path(&self.remote_path_input) {
-                            self.push_error_msg("Server path is invalid");
+                        // The same path on another server is a different place
+                        if self.pairs.iter().any(|(key, value)| *value == self.remote_path_input && self.pair_profile(key) == self.pair_profile_input) {
+                            self.push_error_msg("This server path already in use");
                             return Task::none();
                         }
 
-                        if self.pairs.contains_left(&self.local_path_input) {
-                            self.push_error_msg("This system path already in use");
+                        if self.pair_profile_input != DEFAULT_PROFILE && !self.profiles.contains_key(&self.pair_profile_input) {
+                            self.push_error_msg("No such server profile");
                             return Task::none();
                         }
 
-                        if self.pairs.contains_right(&self.remote_path_input) {
-                            self.push_error_msg("This server path already in use");
-                            return Task::none();
-                        }
-                        
-                        self.local_path_input = match typed_path::NativePath::new(&self.local_path_input).absolutize() {
-                            Ok(path) => { path.to_string() }
-                            Err(e) => {
-                                self.push_error_msg(&format!("Can't absolutize local path {}", e.to_string()));
-                                return Task::none();
+                        let max_deletions = match self.max_deletions_input.trim() {
+                            "" => None,
+                            input => match input.parse::<u64>() {
+                                Ok(max_deletions) => { Some(max_deletions) }
+                                Err(_) => {
+                                    self.push_error_msg("Max deletions must be a number");
+                                    return Task::none();
+                                }
                             }
                         };
-                        self.remote_path_input = match UnixPath::new(&format!("/{}", self.remote_path_input)).absolutize() {
-                            Ok(path) => { path.to_string() }
-                            Err(e) => {
-                                self.push_error_msg(&format!("Can't absolutize remote path {}", e.to_string()));
+
+                        let (upload_limit, download_limit) = match (
+                            parse_optional::<u64>(&self.pair_upload_limit_input),
+                            parse_optional::<u64>(&self.pair_download_limit_input)
+                        ) {
+                            (Ok(upload_limit), Ok(download_limit)) => { (upload_limit, download_limit) }
+                            _ => {
+                                self.push_error_msg("Speed limits must be numbers of KB/s");
                                 return Task::none();
                             }
                         };
 
+                        // States stay valid while the pair points to the same places on the same server
+                        if let Some(EditingState::Edit { key, value }) = &self.editing {
+                            let result = if *key != self.local_path_input {
+                                self.pair_profiles.remove(key);
+                                forget_pair_state(key).and_then(|_| db::delete(PAIRS_TABLE, key))
+                            } else if *value != self.remote_path_input || self.pair_profile(key) != self.pair_profile_input {
+                                engine::forget_sync_state(key)
+                            } else {
+                                Ok(())
+                            };
+
+                            if let Err(e) = result {
+                                self.push_error_msg(&e.to_string());
+                                return Task::none();
+                            }
+                        }
+
+                        if let Err(e) = engine::save_pair_options(&self.local_path_input, &PairOptions { max_deletions, upload_limit, download_limit }) {
+                            self.push_error_msg(&e.to_string());
+                            return Task::none();
+                        }
+
+                        if let Err(e) = profiles::save_pair_profile(&self.local_path_input, &self.pair_profile_input) {
+                            self.push_error_msg(&e.to_string());
+                            return Task::none();
+                        }
+                        self.pair_profiles.insert(self.local_path_input.clone(), self.pair_profile_input.clone());
+
                         match db::write(PAIRS_TABLE, &self.local_path_input, &self.remote_path_input) {
                             Ok(_) => {
                                 self.pairs.insert(
@@ -226,6 +656,12 @@ impl AppState {
                         }
                     }
                     Some(EditingState::Delete { key, .. }) => {
+                        if let Err(e) = forget_pair_state(key) {
+                            self.push_error_msg(&e.to_string());
+                            return Task::none();
+                        }
+                        self.pair_profiles.remove(key);
+
                         match db::delete(PAIRS_TABLE, &key) {
                             Ok(_) => {
                                 self.clear_editing();
@@ -245,41 +681,239 @@ impl AppState {
                 Task::none()
             }
             Message::Synchronize => {
-                self.sync_purpose = Some(SyncPurpose::Synchronize);
+                if self.daemon.is_some() {
+                    self.send_daemon_request(DaemonRequest::Sync);
+                } else {
+                    self.start_sync(SyncPurpose::Synchronize, None);
+                }
                 Task::none()
             }
             Message::SynchronizeCheck => {
-                self.sync_purpose = Some(SyncPurpose::Check);
+                if self.daemon.is_some() {
+                    self.send_daemon_request(DaemonRequest::Check);
+                } else {
+                    self.start_sync(SyncPurpose::Check, None);
+                }
+                Task::none()
+            }
+            Message::CancelSynchronize => {
+                match &self.sync_handle {
+                    Some(sync_handle) => {
+                        // The worker cleans up and answers with StopSynchronize
+                        sync_handle.cancel.cancel();
+                        self.cancelling = true;
+                        self.conflicts.clear();
+                    }
+                    None => {
+                        self.sync_purpose = None;
+                    }
+                }
                 Task::none()
             }
             Message::StopSynchronize => {
                 self.sync_purpose = None;
+                self.cancelling = false;
+                self.conflicts.clear();
+                self.sync_handle = None;
+                self.transfers.clear();
+                self.sync_progress = None;
+                self.sync_scope = None;
+                self.sync_pending_changes();
+                Task::none()
+            }
+            Message::SyncStarted(sync_handle) => {
+                self.sync_handle = Some(sync_handle);
+                self.transfers.clear();
+                self.sync_progress = None;
                 Task::none()
             }
             Message::UpdatePairSyncState(key, syncstate) => {
                 self.pairs_syncstate.insert(key, syncstate);
                 Task::none()
             }
+            Message::TransferProgress(pair_key, local_path, progress) => {
+                self.transfers.insert(local_path, (pair_key, progress));
+                Task::none()
+            }
+            Message::TransferFinished(local_path) => {
+                self.transfers.remove(&local_path);
+                Task::none()
+            }
+            Message::SyncProgress(progress) => {
+                self.sync_progress = Some(progress);
+                Task::none()
+            }
+            Message::ToggleWatch => {
+                if let Err(e) = db::write(SETTINGS_TABLE, "watch", &(!self.watching).to_string()) {
+                    self.push_error_msg(&e.to_string());
+                    return Task::none();
+                }
+
+                self.watching = !self.watching;
+                self.pending_changes.clear();
+                Task::none()
+            }
+            Message::LocalChanges(keys) => {
+                if self.watching && self.daemon.is_none() {
+                    self.pending_changes.extend(keys);
+                    self.sync_pending_changes();
+                }
+                Task::none()
+            }
+            Message::ScheduledSync(keys) => {
+                // A busy run is not interrupted, the next poll notices the changes again
+                if self.sync_purpose.is_none() && self.daemon.is_none() {
+                    self.start_sync(self.schedule.purpose.clone(), keys.map(BTreeSet::from_iter));
+                }
+                Task::none()
+            }
+            Message::DaemonStatusChanged(status) => {
+                self.update_daemon_status(status);
+                Task::none()
+            }
+            Message::SendDaemonRequest(request) => {
+                self.send_daemon_request(request);
+                Task::none()
+            }
+            Message::ConflictDetected(conflict) => {
+                self.conflicts.push_back(conflict);
+                Task::none()
+            }
+            Message::ResolveConflict(local_path, resolution) => {
+                self.conflicts.retain(|conflict| conflict.local_path != local_path);
+
+                if self.daemon.is_some() {
+                    if let ConflictResolution::DecideLater = resolution {
+                        self.deferred_conflicts.insert(local_path);
+                    } else {
+                        self.send_daemon_request(DaemonRequest::Resolve { local_path, resolution });
+                    }
+                } else if let Some(sync_handle) = &mut self.sync_handle
+                    && let Err(e) = sync_handle.resolver.unbounded_send((local_path, resolution)) {
+                    self.push_error_msg(&e.to_string());
+                }
+                Task::none()
+            }
             Message::OpenAuth => {
                 self.decline_editing();
                 self.authorization = true;
                 Task::none()
             }
+            Message::ProfileSelected(profile_name) => {
+                self.select_profile(profile_name);
+                Task::none()
+            }
             Message::SaveAuth => {
-                if let Err(e) = db::write(AUTH_TABLE, "host", &self.host) {
+                let profile_name = self.profile_name_input.trim().to_owned();
+                let profile = ServerProfile {
+                    host: self.host.clone(),
+                    login: self.login.clone(),
+                    password: self.password.clone(),
+                    private_key: self.private_key.clone(),
+                    host_key: self.host_key.trim().to_owned()
+                };
+
+                if let Err(e) = profiles::save_profile(&profile_name, &profile) {
                     self.push_error_msg(&e.to_string());
                     return Task::none();
                 }
-                if let Err(e) = db::write(AUTH_TABLE, "login", &self.login) {
+
+                self.profiles.insert(profile_name.clone(), profile);
+                self.profile_name_input = profile_name;
+                self.authorization = false;
+                Task::none()
+            }
+            Message::DeleteProfile => {
+                if let Err(e) = profiles::delete_profile(&self.profile_name_input) {
                     self.push_error_msg(&e.to_string());
                     return Task::none();
                 }
-                if let Err(e) = db::write(AUTH_TABLE, "password", &self.password) {
+
+                self.profiles.remove(&self.profile_name_input);
+                self.select_profile(default_profile_name(&self.profiles));
+                Task::none()
+            }
+            Message::OpenSettings => {
+                self.decline_editing();
+                self.settings = true;
+                Task::none()
+            }
+            Message::SaveSettings => {
+                let max_transfers = match self.max_transfers_input.trim().parse::<usize>() {
+                    Ok(max_transfers) if max_transfers > 0 => { max_transfers }
+                    _ => {
+                        self.push_error_msg("Parallel transfers must be a positive number");
+                        return Task::none();
+                    }
+                };
+
+                let max_attempts = match self.max_attempts_input.trim().parse::<u32>() {
+                    Ok(max_attempts) if max_attempts > 0 => { max_attempts }
+                    _ => {
+                        self.push_error_msg("Request attempts must be a positive number");
+                        return Task::none();
+                    }
+                };
+
+                let (upload_limit, download_limit) = match (
+                    parse_optional::<u64>(&self.upload_limit_input),
+                    parse_optional::<u64>(&self.download_limit_input)
+                ) {
+                    (Ok(upload_limit), Ok(download_limit)) => { (upload_limit, download_limit) }
+                    _ => {
+                        self.push_error_msg("Speed limits must be numbers of KB/s");
+                        return Task::none();
+                    }
+                };
+
+                let unlimited_hours = match (
+                    parse_optional_time(&self.unlimited_from_input),
+                    parse_optional_time(&self.unlimited_to_input)
+                ) {
+                    (Ok(Some(from)), Ok(Some(to))) => { Some((from, to)) }
+                    (Ok(None), Ok(None)) => { None }
+                    _ => {
+                        self.push_error_msg("Unlimited hours need both times in HH:MM format");
+                        return Task::none();
+                    }
+                };
+
+                let (sync_interval, poll_interval) = match (
+                    parse_optional::<u64>(&self.sync_interval_input),
+                    parse_optional::<u64>(&self.poll_interval_input)
+                ) {
+                    (Ok(sync_interval), Ok(poll_interval)) if sync_interval != Some(0) && poll_interval != Some(0) => { (sync_interval, poll_interval) }
+                    _ => {
+                        self.push_error_msg("Intervals must be positive numbers of minutes");
+                        return Task::none();
+                    }
+                };
+                let scheduled_purpose = if self.scheduled_synchronize_input { "synchronize" } else { "check" };
+
+                if let Err(e) = write_setting("max_transfers", Some(max_transfers.to_string()))
+                    .and_then(|_| write_setting("max_attempts", Some(max_attempts.to_string())))
+                    .and_then(|_| write_setting("upload_limit", upload_limit.map(|limit| limit.to_string())))
+                    .and_then(|_| write_setting("download_limit", download_limit.map(|limit| limit.to_string())))
+                    .and_then(|_| write_setting("unlimited_from", unlimited_hours.map(|(from, _)| from.format("%H:%M").to_string())))
+                    .and_then(|_| write_setting("unlimited_to", unlimited_hours.map(|(_, to)| to.format("%H:%M").to_string())))
+                    .and_then(|_| write_setting("sync_interval", sync_interval.map(|minutes| minutes.to_string())))
+                    .and_then(|_| write_setting("poll_interval", poll_interval.map(|minutes| minutes.to_string())))
+                    .and_then(|_| write_setting("scheduled_purpose", Some(scheduled_purpose.to_string()))) {
                     self.push_error_msg(&e.to_string());
                     return Task::none();
                 }
 
-                self.authorization = false;
+                self.sync_settings.max_transfers = max_transfers;
+                self.sync_settings.max_attempts = max_attempts;
+                self.sync_settings.upload_limit = upload_limit;
+                self.sync_settings.download_limit = download_limit;
+                self.sync_settings.unlimited_hours = unlimited_hours;
+                self.schedule = Schedule {
+                    sync_interval: sync_interval.map(|minutes| Duration::from_secs(minutes * 60)),
+                    poll_interval: poll_interval.map(|minutes| Duration::from_secs(minutes * 60)),
+                    purpose: if self.scheduled_synchronize_input { SyncPurpose::Synchronize } else { SyncPurpose::Check }
+                };
+                self.settings = false;
                 Task::none()
             }
             Message::ShowError(error_msg) => {
@@ -293,6 +927,74 @@ impl AppState {
         }
     }
 
+    fn start_sync(self: &mut Self, purpose: SyncPurpose, scope: Option<BTreeSet<String>>) {
+        // A full run covers every change noticed so far
+        if scope.is_none() {
+            self.pending_changes.clear();
+        }
+
+        self.sync_purpose = Some(purpose);
+        self.sync_scope = scope;
+        self.sync_run += 1;
+    }
+
+    fn sync_pending_changes(self: &mut Self) {
+        // Changes made during a running synchronization wait for it to finish
+        if self.sync_purpose.is_some() || self.pending_changes.is_empty() {
+            return;
+        }
+
+        let scope = std::mem::take(&mut self.pending_changes);
+        self.start_sync(SyncPurpose::Synchronize, Some(scope));
+    }
+
+    fn update_daemon_status(self: &mut Self, status: Option<DaemonStatus>) {
+        if status.is_none() && self.daemon.is_some() {
+            // The daemon stopped and left the database to the window
+            let error_msgs = std::mem::take(&mut self.error_msgs);
+            *self = AppState::new();
+            self.error_msgs.extend(error_msgs);
+            return;
+        }
+
+        if let Some(status) = &status {
+            self.pairs = status.pairs.iter().map(|pair| (pair.local_path.clone(), pair.remote_path.clone())).collect();
+            self.pair_profiles = status.pairs.iter().map(|pair| (pair.local_path.clone(), pair.profile.clone())).collect();
+
+            for pair in &status.pairs {
+                if let Some(syncstate) = &pair.state {
+                    self.pairs_syncstate.insert(pair.local_path.clone(), syncstate.clone());
+                }
+            }
+
+            // Errors from before the window connected are not shown
+            let last_error = status.errors.last().map(|(number, _)| *number).unwrap_or(0);
+            if self.daemon.is_some() {
+                for (number, e) in &status.errors {
+                    if *number > self.daemon_errors_seen {
+                        self.push_error_msg(e);
+                    }
+                }
+            }
+            self.daemon_errors_seen = self.daemon_errors_seen.max(last_error);
+            self.sync_progress = status.progress.clone();
+            self.conflicts = status.conflicts
+                .iter()
+                .filter(|conflict| !self.deferred_conflicts.contains(&conflict.local_path))
+                .cloned()
+                .collect();
+        }
+
+        self.daemon = status;
+    }
+
+    fn send_daemon_request(self: &mut Self, request: DaemonRequest) {
+        match daemon::request(request) {
+            Ok(status) => { self.update_daemon_status(Some(status)); }
+            Err(e) => { self.push_error_msg(&e.to_string()); }
+        }
+    }
+
     fn push_error_msg(self: &mut Self, msg: &str) {
         self.error_msgs.push_back(msg.to_string());
     }
@@ -312,17 +1014,47 @@ impl AppState {
     fn clear_editing(self: &mut Self) {
         self.local_path_input.clear();
         self.remote_path_input.clear();
+        self.max_deletions_input.clear();
+        self.pair_upload_limit_input.clear();
+        self.pair_download_limit_input.clear();
+        self.pair_profile_input = default_profile_name(&self.profiles);
         self.editing = None;
     }
 
+    fn pair_profile(self: &Self, key: &str) -> &str {
+        self.pair_profiles.get(key).map(String::as_str).unwrap_or(DEFAULT_PROFILE)
+    }
+
+    fn select_profile(self: &mut Self, profile_name: String) {
+        let profile = self.profiles.get(&profile_name).cloned().unwrap_or_default();
+        self.profile_name_input = profile_name;
+        self.host = profile.host;
+        self.login = profile.login;
+        self.password = profile.password;
+        self.private_key = profile.private_key;
+        self.host_key = profile.host_key;
+    }
+
     fn input_editing_fields(self: &'_ Self) -> Element<'_, Message> {
-        row![
-            text_input("Local path", &self.local_path_input)
-                .on_input(Message::LocalPathInputChanged),
-            text("<=>"),
-            text_input("Remote path", &self.remote_path_input)
-                .on_input(Message::RemotePathInputChanged)
-        ].spacing(8).into()
+        column![
+            row![
+                text_input("Local path", &self.local_path_input)
+                    .on_input(Message::LocalPathInputChanged),
+                text("<=>"),
+                text_input("Remote path", &self.remote_path_input)
+                    .on_input(Message::RemotePathInputChanged),
+                pick_list(self.profiles.keys().cloned().collect::<Vec<String>>(), Some(self.pair_profile_input.clone()), Message::PairProfileSelected)
+                    .placeholder("Server profile")
+            ].spacing(8),
+            text_input(&format!("Max deletions per synchronization (default {})", engine::DEFAULT_MAX_DELETIONS), &self.max_deletions_input)
+                .on_input(Message::MaxDeletionsInputChanged),
+            row![
+                text_input("Upload limit, KB/s (no limit)", &self.pair_upload_limit_input)
+                    .on_input(Message::PairUploadLimitInputChanged),
+                text_input("Download limit, KB/s (no limit)", &self.pair_download_limit_input)
+                    .on_input(Message::PairDownloadLimitInputChanged)
+            ].spacing(8)
+        ].spacing(3).into()
     }
 
     fn editing_buttons(self: &'_ Self) -> Element<'_, Message> {
@@ -369,10 +1101,50 @@ impl AppState {
             content = content.push(
                 column![
                     text("Authorization"),
-                    text_input("Host", &self.host).width(Fill).on_input(Message::HostInputChanged),
+                    row![
+                        pick_list(
+                            self.profiles.keys().cloned().collect::<Vec<String>>(),
+                            self.profiles.contains_key(&self.profile_name_input).then(|| self.profile_name_input.clone()),
+                            Message::ProfileSelected
+                        ).placeholder("New profile"),
+                        text_input("Profile name", &self.profile_name_input).width(Fill).on_input(Message::ProfileNameInputChanged)
+                    ].spacing(8),
+                    text_input("Host (https://..., sftp://host/path, s3://host/bucket or file:///path)", &self.host).width(Fill).on_input(Message::HostInputChanged),
                     text_input("Login", &self.login).width(Fill).on_input(Message::LoginInputChanged),
                     text_input("Password", &self.password).width(Fill).on_input(Message::PasswordInputChanged),
-                    button(text("Save")).on_press(Message::SaveAuth),
+                    text_input("Private key path (SFTP, optional)", &self.private_key).width(Fill).on_input(Message::PrivateKeyInputChanged),
+                    text_input("Host key fingerprint SHA256:... (SFTP server missing from known_hosts)", &self.host_key).width(Fill).on_input(Message::HostKeyInputChanged),
+                    row![
+                        button(text("Save")).on_press(Message::SaveAuth),
+                        button(text("Delete profile")).on_press(Message::DeleteProfile)
+                    ].spacing(8),
+                ].spacing(3),
+            );
+            content = content.push(rule::horizontal(3));
+        }
+
+        if self.settings {
+            content = content.push(
+                column![
+                    text("Settings"),
+                    text_input("Parallel transfers", &self.max_transfers_input).width(Fill).on_input(Message::MaxTransfersInputChanged),
+                    text_input("Request attempts", &self.max_attempts_input).width(Fill).on_input(Message::MaxAttemptsInputChanged),
+                    row![
+                        text_input("Upload limit, KB/s (no limit)", &self.upload_limit_input).width(Fill).on_input(Message::UploadLimitInputChanged),
+                        text_input("Download limit, KB/s (no limit)", &self.download_limit_input).width(Fill).on_input(Message::DownloadLimitInputChanged)
+                    ].spacing(8),
+                    row![
+                        text("No limits from"),
+                        text_input("HH:MM", &self.unlimited_from_input).width(Fill).on_input(Message::UnlimitedFromInputChanged),
+                        text("to"),
+                        text_input("HH:MM", &self.unlimited_to_input).width(Fill).on_input(Message::UnlimitedToInputChanged)
+                    ].spacing(8),
+                    row![
+                        text_input("Automatic run every N minutes (off)", &self.sync_interval_input).width(Fill).on_input(Message::SyncIntervalInputChanged),
+                        text_input("Poll for changes every N minutes (off)", &self.poll_interval_input).width(Fill).on_input(Message::PollIntervalInputChanged),
+                        button(text(if self.scheduled_synchronize_input { "Runs synchronize" } else { "Runs check" })).on_press(Message::ScheduledPurposeToggled)
+                    ].spacing(8),
+                    button(text("Save")).on_press(Message::SaveSettings),
                 ].spacing(3),
             );
             content = content.push(rule::horizontal(3));
@@ -389,28 +1161,103 @@ impl AppState {
             content = content.push(rule::horizontal(3));
         }
 
-        content = content.push(
-            button(text("New pair").center().width(Fill))
-                .width(Fill)
-                .on_press(Message::CreatePair),
-        );
+        if let Some(conflict) = self.conflicts.front() {
+            content = content.push(
+                column![
+                    text(format!("({}) Conflict: {} <=> {}", self.conflicts.len(), conflict.local_path, conflict.remote_path)),
+                    text(format!("Local: {} bytes, modified {}", conflict.local_size, format_datetime(&conflict.local_modified))),
+                    text(format!("Remote: {} bytes, modified {}", conflict.remote_size, format_datetime(&conflict.remote_modified))),
+                    row![
+                        button(text("Keep local")).on_press(Message::ResolveConflict(conflict.local_path.clone(), ConflictResolution::KeepLocal)),
+                        button(text("Keep remote")).on_press(Message::ResolveConflict(conflict.local_path.clone(), ConflictResolution::KeepRemote)),
+                        button(text("Keep both")).on_press(Message::ResolveConflict(conflict.local_path.clone(), ConflictResolution::KeepBoth)),
+                        button(text("Decide later")).on_press(Message::ResolveConflict(conflict.local_path.clone(), ConflictResolution::DecideLater))
+                    ].spacing(8)
+                ]
+                .spacing(3),
+            );
+            content = content.push(rule::horizontal(3));
+        }
+
+        // Pairs, profiles and settings are changed only while the window owns the database
+        let owns_database = self.daemon.is_none();
+
+        if owns_database {
+            content = content.push(
+                button(text("New pair").center().width(Fill))
+                    .width(Fill)
+                    .on_press(Message::CreatePair),
+            );
+        }
 
         let mut pairs_content = column!().spacing(2);
 
         for (key, value) in self.pairs.iter() {
+            // Servers are named only when there is more than one
+            let value = if self.profiles.len() > 1 || self.pair_profiles.values().any(|profile| profile != DEFAULT_PROFILE) {
+                format!("{}: {}", self.pair_profile(key), value)
+            } else {
+                value.clone()
+            };
+
             let syncstate_description = match self.pairs_syncstate.get(key) {
                 Some(SyncState::Synchronized) => "✅",
                 Some(SyncState::UnsynchronizedLocal) => "☁️➡️💻",
                 Some(SyncState::UnsynchronizedRemote) => "💻➡️☁️",
+                Some(SyncState::Conflict) => "⚠️",
                 Some(SyncState::CantSynchronize) => "❌",
+                Some(SyncState::Cancelled) => "⏹️",
                 None => "❓"
             };
 
-            pairs_content = pairs_content.push(
+            let pair_transfers: Vec<(&String, &TransferProgress)> = self.transfers
+                .iter()
+                .filter(|(_, (pair_key, _))| pair_key == key)
+                .map(|(local_path, (_, progress))| (local_path, progress))
+                .collect();
+
+            let mut pair_row = row![
+                text(format!("({syncstate_description}) {key} <=> {value}")).width(Fill)
+            ]
+            .spacing(8);
+
+            if !pair_transfers.is_empty() {
+                let pair_progress = TransferProgress {
+                    transferred: pair_transfers.iter().map(|(_, progress)| progress.transferred).sum(),
+                    total: pair_transfers.iter().map(|(_, progress)| progress.total).sum(),
+                    bytes_per_second: pair_transfers.iter().map(|(_, progress)| progress.bytes_per_second).sum(),
+                    eta: None
+                };
+                pair_row = pair_row.push(progress_bar(0.0..=1.0, progress_fraction(&pair_progress)).length(120.0).girth(16.0));
+            }
+
+            if owns_database {
+                pair_row = pair_row
+                    .push(button(text("Edit")).on_press(Message::EditPair(key.clone())))
+                    .push(button(text("Delete")).on_press(Message::DeletePair(key.clone())));
+            }
+            pairs_content = pairs_content.push(pair_row);
+
+            for (local_path, progress) in pair_transfers {
+                let file_name = Path::new(local_path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+
+                pairs_content = pairs_content.push(
+                    row![
+                        text(format!("    {}", file_name)).width(Fill),
+                        progress_bar(0.0..=1.0, progress_fraction(progress)).length(120.0).girth(12.0),
+                        text(format_progress(progress))
+                    ]
+                    .spacing(8),
+                );
+            }
+        }
+
+        if let (Some(_), Some(progress)) = (&self.sync_purpose, &self.sync_progress) {
+            content = content.push(
                 row![
-                    text(format!("({syncstate_description}) {key} <=> {value}")).width(Fill),
-                    button(text("Edit")).on_press(Message::EditPair(key.clone())),
-                    button(text("Delete")).on_press(Message::DeletePair(key.clone()))
+                    text("Total").width(Fill),
+                    progress_bar(0.0..=1.0, progress_fraction(progress)).length(200.0).girth(16.0),
+                    text(format_progress(progress))
                 ]
                 .spacing(8),
             );
@@ -418,20 +1265,44 @@ impl AppState {
 
         content = content.push(scrollable(pairs_content).height(Fill));
 
-        if !self.authorization && self.sync_purpose.is_none() {
+        if let Some(status) = &self.daemon {
+            content = content.push(
+                row![
+                    text(format!("Daemon: {}", status.description())).width(Fill),
+                    if status.paused {
+                        button(text("Resume")).on_press(Message::SendDaemonRequest(DaemonRequest::Resume))
+                    } else {
+                        button(text("Pause")).on_press(Message::SendDaemonRequest(DaemonRequest::Pause))
+                    }
+                ]
+                .spacing(8),
+            );
+        }
+
+        if !self.authorization && !self.settings && self.sync_purpose.is_none() {
             content = content.push(column![
                 button(text("Synchronize").center().width(Fill)).width(Fill).on_press(Message::Synchronize),
-                button(text("Check").center().width(Fill)).width(Fill).on_press(Message::SynchronizeCheck),
-                button(text("Authorization").center().width(Fill)).width(Fill).on_press(Message::OpenAuth)
+                button(text("Check").center().width(Fill)).width(Fill).on_press(Message::SynchronizeCheck)
             ].spacing(8));
+
+            if owns_database {
+                content = content.push(column![
+                    button(text(if self.watching { "Stop watching changes" } else { "Watch changes" }).center().width(Fill)).width(Fill).on_press(Message::ToggleWatch),
+                    button(text("Authorization").center().width(Fill)).width(Fill).on_press(Message::OpenAuth),
+                    button(text("Settings").center().width(Fill)).width(Fill).on_press(Message::OpenSettings)
+                ].spacing(8));
+            }
         }
         
         match self.sync_purpose {
+            Some(_) if self.cancelling => content = content.push(
+                button(text("Cancelling...").center().width(Fill)).width(Fill)
+            ),
             Some(SyncPurpose::Synchronize) => content = content.push(
-                button(text("Stop synchronize").center().width(Fill)).width(Fill).on_press(Message::StopSynchronize)
+                button(text("Stop synchronize").center().width(Fill)).width(Fill).on_press(Message::CancelSynchronize)
             ),
             Some(SyncPurpose::Check) => content = content.push(
-                button(text("Stop checking").center().width(Fill)).width(Fill).on_press(Message::StopSynchronize)
+                button(text("Stop checking").center().width(Fill)).width(Fill).on_press(Message::CancelSynchronize)
             ),
             None => {}
         }
@@ -440,33 +1311,52 @@ impl AppState {
     }
 
     fn subscription(self: &Self) -> Subscription<Message> {
+        Subscription::batch([
+            self.sync_subscription(),
+            self.watch_subscription(),
+            self.schedule_subscription(),
+            Subscription::run(daemon::watch_status)
+        ])
+    }
+
+    fn sync_pairs(self: &Self) -> Vec<SyncPair> {
+        self.pairs
+        .iter()
+        .map(|(k, v)| SyncPair {
+            local_path: k.clone(),
+            remote_path: v.clone(),
+            profile: self.pair_profile(k).to_owned()
+        })
+        .collect()
+    }
+
+    fn sync_subscription(self: &Self) -> Subscription<Message> {
         match &self.sync_purpose {
             Some(sync_purpose) => {
-                let pairs_vec: Arc<Vec<(String, String)>> = Arc::new(
-                    self.pairs
-                    .iter()
-                    .map(|(k, v)| {(k.clone(), v.clone())})
+                let pairs_vec: Arc<Vec<SyncPair>> = Arc::new(
+                    self.sync_pairs()
+                    .into_iter()
+                    .filter(|pair| self.sync_scope.as_ref().is_none_or(|scope| scope.contains(&pair.local_path)))
                     .collect()
                 );
 
                 Subscription::run_with(
                     (
-                        self.host.clone(),
-                        self.login.clone(),
-                        self.password.clone(),
+                        self.sync_run,
+                        SyncEngine::new(self.profiles.clone(), self.sync_settings.clone()),
                         pairs_vec,
                         sync_purpose.clone()
                     ),
-                    |(host, login, password, pairs_vec, sync_purpose)| {
+                    |(_, engine, pairs_vec, sync_purpose)| {
+                        let engine = engine.clone();
                         let pairs_vec = pairs_vec.clone();
-                        let host = host.clone();
-                        let login = login.clone();
-                        let password = password.clone();
                         let sync_purpose = sync_purpose.clone();
                         stream::channel(100, |output| async move {
                             let rt = Runtime::new().unwrap();
                             rt.block_on(async {
-                                webdav::run_sync(output, host, login, password, pairs_vec, sync_purpose).await;
+                                let (events, receiver) = mpsc::channel(100);
+                                let messages = receiver.map(|event| Ok(Message::from(event))).forward(output);
+                                let _ = tokio::join!(engine.run(events, pairs_vec, sync_purpose, ConflictPolicy::Ask), messages);
                             });
                         })
                     }
@@ -475,4 +1365,49 @@ impl AppState {
             None => Subscription::none()
         }
     }
-}
\ No newline at end of file
+
+    fn watch_subscription(self: &Self) -> Subscription<Message> {
+        if !self.watching || self.daemon.is_some() {
+            return Subscription::none();
+        }
+
+        let pairs_vec: Arc<Vec<SyncPair>> = Arc::new(self.sync_pairs());
+
+        Subscription::run_with(pairs_vec, |pairs_vec| {
+            let pairs_vec = pairs_vec.clone();
+            stream::channel(100, |output| async move {
+                let rt = Runtime::new().unwrap();
+                rt.block_on(async {
+                    watcher::watch_pairs(output, pairs_vec).await;
+                });
+            })
+        })
+    }
+
+    fn schedule_subscription(self: &Self) -> Subscription<Message> {
+        if !self.schedule.is_enabled() || self.daemon.is_some() {
+            return Subscription::none();
+        }
+
+        let pairs_vec: Arc<Vec<SyncPair>> = Arc::new(self.sync_pairs());
+
+        Subscription::run_with(
+            (
+                SyncEngine::new(self.profiles.clone(), self.sync_settings.clone()),
+                pairs_vec,
+                self.schedule.clone()
+            ),
+            |(engine, pairs_vec, schedule)| {
+                let engine = engine.clone();
+                let pairs_vec = pairs_vec.clone();
+                let schedule = schedule.clone();
+                stream::channel(100, |output| async move {
+                    let rt = Runtime::new().unwrap();
+                    rt.block_on(async {
+                        scheduler::run_schedule(output, engine, pairs_vec, schedule).await;
+                    });
+                })
+            }
+        )
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, anyhow};
+
+use crate::{db::{self, AUTH_TABLE, PAIR_PROFILES_TABLE, PAIRS_TABLE, PROFILES_TABLE}, engine};
+
+// Pairs added before profiles existed and pairs without a chosen profile use this one
+pub const DEFAULT_PROFILE: &str = "default";
+
+const LEGACY_AUTH_KEYS: [&str; 4] = ["host", "login", "password", "private_key"];
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, Hash, PartialEq)]
+pub struct ServerProfile {
+    pub host: String,
+    pub login: String,
+    // Also the passphrase of the private key
+    pub password: String,
+    pub private_key: String,
+    // SHA256 fingerprint of an SFTP server key that is trusted without known_hosts
+    pub host_key: String
+}
+
+#[derive(serde::Deserialize)]
+struct LegacyServerProfile {
+    host: String,
+    login: String,
+    password: String,
+    private_key: String
+}
+
+
+// PROFILES
+pub fn load_profiles() -> BTreeMap<String, ServerProfile> {
+    migrate_legacy_auth();
+
+    db::read_bytes_with_prefix(PROFILES_TABLE, "")
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(name, data)| Some((name, decode_profile(&data)?)))
+        .collect()
+}
+
+pub fn save_profile(name: &str, profile: &ServerProfile) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(anyhow!("Profile name is empty"));
+    }
+
+    // States saved against another server would turn its differences into deletions
+    let old_profile = db::read_bytes(PROFILES_TABLE, name)?.and_then(|data| decode_profile(&data));
+    if old_profile.is_some_and(|old_profile| old_profile.host != profile.host) {
+        for local_path in pairs_of_profile(name)? {
+            engine::forget_sync_state(&local_path)?;
+        }
+    }
+
+    db::write_bytes(PROFILES_TABLE, name, &postcard::to_allocvec(profile)?)?;
+    Ok(())
+}
+
+fn decode_profile(data: &[u8]) -> Option<ServerProfile> {
+    if let Ok((profile, [])) = postcard::take_from_bytes::<ServerProfile>(data) {
+        return Some(profile);
+    }
+
+    let legacy = postcard::from_bytes::<LegacyServerProfile>(data).ok()?;
+    Some(ServerProfile {
+        host: legacy.host,
+        login: legacy.login,
+        password: legacy.password,
+        private_key: legacy.private_key,
+        host_key: String::new()
+    })
+}
+
+pub fn delete_profile(name: &str) -> Result<()> {
+    if let Some(local_path) = pairs_of_profile(name)?.first() {
+        return Err(anyhow!("Profile {} is used by the pair of {}", name, local_path));
+    }
+
+    db::delete_bytes(PROFILES_TABLE, name)?;
+    Ok(())
+}
+
+// The single host of older versions becomes the default profile
+fn migrate_legacy_auth() {
+    let legacy_value = |key: &str| db::read(AUTH_TABLE, key).ok().flatten();
+    let Some(host) = legacy_value("host") else {
+        return;
+    };
+
+    if let Ok(None) = db::read_bytes(PROFILES_TABLE, DEFAULT_PROFILE) {
+        let profile = ServerProfile {
+            host: host,
+            login: legacy_value("login").unwrap_or_default(),
+            password: legacy_value("password").unwrap_or_default(),
+            private_key: legacy_value("private_key").unwrap_or_default(),
+            host_key: String::new()
+        };
+        if save_profile(DEFAULT_PROFILE, &profile).is_err() {
+            return;
+        }
+    }
+
+    for key in LEGACY_AUTH_KEYS {
+        let _ = db::delete(AUTH_TABLE, key);
+    }
+}
+
+
+// PAIR PROFILES
+fn pairs_of_profile(name: &str) -> Result<Vec<String>> {
+    Ok(db::read_as_map(PAIRS_TABLE)?
+        .into_keys()
+        .filter(|local_path| load_pair_profile(local_path) == name)
+        .collect())
+}
+
+pub fn load_pair_profile(local_path: &str) -> String {
+    db::read(PAIR_PROFILES_TABLE, local_path)
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_owned())
+}
+
+pub fn save_pair_profile(local_path: &str, name: &str) -> Result<()> {
+    db::write(PAIR_PROFILES_TABLE, local_path, name)?;
+    Ok(())
+}
@@ -0,0 +1,220 @@
+use std::{fmt, future::Future, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use reqwest::{Response, StatusCode, header::RETRY_AFTER};
+use reqwest_dav::{DecodeError, Error as DavError};
+use chrono::{DateTime, Utc};
+use anyhow::Result;
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+const RETRY_AFTER_LIMIT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    max_attempts: u32
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(max_attempts: u32) -> Self {
+        RetryPolicy { max_attempts: max_attempts.max(1) }
+    }
+}
+
+#[derive(Debug)]
+struct TransientStatusError {
+    status: StatusCode,
+    retry_after: Option<Duration>
+}
+
+impl fmt::Display for TransientStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server is temporarily unavailable. Code: {}", self.status)
+    }
+}
+
+impl std::error::Error for TransientStatusError {}
+
+pub(crate) async fn with_retry<T, F, Fut>(retry: RetryPolicy, mut operation: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>
+{
+    let mut attempt = 1;
+
+    loop {
+        let e = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e
+        };
+
+        let Some(retry_after) = transient_error_delay(&e) else {
+            return Err(e);
+        };
+
+        if attempt >= retry.max_attempts {
+            return Err(e);
+        }
+
+        tokio::time::sleep(retry_after.unwrap_or_else(|| backoff_delay(attempt))).await;
+        attempt += 1;
+    }
+}
+
+pub(crate) fn check_transient_status(response: Response) -> Result<Response> {
+    if !is_transient_status(response.status().as_u16()) {
+        return Ok(response);
+    }
+
+    Err(TransientStatusError {
+        status: response.status(),
+        retry_after: response.headers().get(RETRY_AFTER).and_then(|value| value.to_str().ok()).and_then(parse_retry_after)
+    }.into())
+}
+
+fn is_transient_status(code: u16) -> bool {
+    // Everything else, including 401, 403 and 507, fails the same way on every attempt
+    matches!(code, 408 | 423 | 425 | 429 | 500 | 502 | 503 | 504)
+}
+
+fn is_transient_reqwest_error(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => is_transient_status(status.as_u16()),
+        None => e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+    }
+}
+
+// None means the error is permanent, Some(None) means the delay is up to the backoff
+fn transient_error_delay(e: &anyhow::Error) -> Option<Option<Duration>> {
+    for cause in e.chain() {
+        if let Some(status_error) = cause.downcast_ref::<TransientStatusError>() {
+            return Some(status_error.retry_after);
+        }
+
+        let transient = match cause.downcast_ref::<DavError>() {
+            Some(DavError::Reqwest(reqwest_error)) => is_transient_reqwest_error(reqwest_error),
+            Some(DavError::Decode(DecodeError::StatusMismatched(status_error))) => is_transient_status(status_error.response_code),
+            Some(DavError::Decode(DecodeError::Server(server_error))) => is_transient_status(server_error.response_code),
+            Some(_) => false,
+            None => match cause.downcast_ref::<reqwest::Error>() {
+                Some(reqwest_error) => is_transient_reqwest_error(reqwest_error),
+                None => continue
+            }
+        };
+
+        return transient.then_some(None);
+    }
+
+    None
+}
+
+// Seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => (DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or_default()
+    };
+
+    Some(delay.min(RETRY_AFTER_LIMIT))
+}
+
+fn backoff_delay(attempt: u32) -> Duration {
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(RETRY_MAX_DELAY);
+
+    // Sub-second clock noise is enough to keep parallel transfers from retrying in lockstep
+    let jitter = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos() % 1000)
+        .unwrap_or_default();
+
+    delay / 2 + delay / 2 * jitter / 1000
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    fn transient_error(retry_after: Option<Duration>) -> anyhow::Error {
+        TransientStatusError { status: StatusCode::SERVICE_UNAVAILABLE, retry_after: retry_after }.into()
+    }
+
+    #[test]
+    fn retries_only_transient_statuses() {
+        for code in [408, 423, 425, 429, 500, 502, 503, 504] {
+            assert!(is_transient_status(code), "{}", code);
+        }
+        for code in [400, 401, 403, 404, 405, 409, 412, 413, 501, 507] {
+            assert!(!is_transient_status(code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn finds_transient_errors_under_context() {
+        let e = transient_error(Some(Duration::from_secs(7))).context("Upload failed");
+        assert_eq!(transient_error_delay(&e), Some(Some(Duration::from_secs(7))));
+        assert_eq!(transient_error_delay(&anyhow!("Not found")), None);
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("86400"), Some(RETRY_AFTER_LIMIT));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn parses_retry_after_dates() {
+        let date = (Utc::now() + chrono::Duration::seconds(60)).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let delay = parse_retry_after(&date).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60), "{:?}", delay);
+
+        // A date in the past means retrying right away
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT"), Some(Duration::ZERO));
+        let far_date = (Utc::now() + chrono::Duration::days(1)).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        assert_eq!(parse_retry_after(&far_date), Some(RETRY_AFTER_LIMIT));
+    }
+
+    #[test]
+    fn keeps_backoff_with_jitter_within_bounds() {
+        let expected = [(1, 500), (2, 1_000), (3, 2_000), (6, 16_000), (7, 30_000), (40, 30_000)];
+
+        for (attempt, max_millis) in expected {
+            let backoff = backoff_delay(attempt);
+            let max_delay = Duration::from_millis(max_millis);
+            assert!(backoff >= max_delay / 2 && backoff <= max_delay, "{}: {:?}", attempt, backoff);
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = with_retry(RetryPolicy::new(3), || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(transient_error(Some(Duration::ZERO)))
+        }).await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn fails_permanent_errors_at_once() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = with_retry(RetryPolicy::new(3), || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(anyhow!("Upload request unsuccess. Code: 403 Forbidden"))
+        }).await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+}
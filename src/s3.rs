@@ -0,0 +1,738 @@
+use std::collections::BTreeSet;
+
+use reqwest::{Body, Client, Method, RequestBuilder, StatusCode, Url, header::{AUTHORIZATION, CONTENT_LENGTH, ETAG, HeaderMap, IF_MATCH, LAST_MODIFIED, RANGE}};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use anyhow::{Result, anyhow};
+
+use crate::{backend::{HASH_ALGORITHM, PartialUpload, RemoteBackend, RemoteEntry, RemoteFile, RemoteRead, UploadSource, clear_partial_upload, load_partial_upload, save_partial_upload}, retry::{RetryPolicy, check_transient_status, with_retry}};
+
+const HTTPS_SCHEME: &str = "s3://";
+const HTTP_SCHEME: &str = "s3+http://";
+const DEFAULT_REGION: &str = "us-east-1";
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const MTIME_HEADER: &str = "x-amz-meta-mtime";
+const CHECKSUM_HEADER: &str = "x-amz-meta-checksum";
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
+// Everything except unreserved characters, as SigV4 wants it
+const URI_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+const KEY_ENCODE_SET: &AsciiSet = &URI_ENCODE_SET.remove(b'/');
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct MultipartUpload {
+    upload_id: String,
+    part_size: u64,
+    part_etags: Vec<String>
+}
+
+// Buckets are addressed path-style, which MinIO and Ceph RGW serve without DNS for every bucket
+pub struct S3Backend {
+    client: Client,
+    endpoint: String,
+    authority: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    retry: RetryPolicy
+}
+
+impl S3Backend {
+    // Hosts like s3://minio.local:9000/bucket/prefix?region=eu-west-1 pick this backend, s3+http:// goes without TLS
+    pub fn from_host(host: &str, access_key: &str, secret_key: &str, max_attempts: u32) -> Option<Result<Self>> {
+        let (scheme, address) = match (host.strip_prefix(HTTPS_SCHEME), host.strip_prefix(HTTP_SCHEME)) {
+            (Some(address), _) => ("https", address),
+            (_, Some(address)) => ("http", address),
+            _ => return None
+        };
+
+        Some(S3Backend::new(scheme, address, access_key, secret_key, max_attempts))
+    }
+
+    fn new(scheme: &str, address: &str, access_key: &str, secret_key: &str, max_attempts: u32) -> Result<Self> {
+        let (address, region) = address.split_once("?region=").unwrap_or((address, DEFAULT_REGION));
+        let (authority, path) = address.split_once('/').unwrap_or((address, ""));
+        let path = path.trim_matches('/');
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+
+        if bucket.is_empty() {
+            return Err(anyhow!("No bucket in {}", address));
+        }
+
+        // The signature covers the Host header, which leaves out default ports
+        let url = Url::parse(&format!("{}://{}", scheme, authority))?;
+        let host = url.host_str().ok_or_else(|| anyhow!("No server in {}", address))?;
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned()
+        };
+
+        Ok(S3Backend {
+            client: Client::builder().build()?,
+            endpoint: format!("{}://{}", scheme, authority),
+            authority: authority,
+            bucket: bucket.to_owned(),
+            prefix: if prefix.is_empty() { String::new() } else { format!("{}/", prefix.trim_matches('/')) },
+            region: region.to_owned(),
+            access_key: access_key.to_owned(),
+            secret_key: secret_key.to_owned(),
+            retry: RetryPolicy::new(max_attempts)
+        })
+    }
+
+    fn object_key(self: &Self, path: &str) -> String {
+        format!("{}{}", self.prefix, path.trim_matches('/'))
+    }
+
+    // Keys below a directory start with it and a '/'
+    fn dir_prefix(self: &Self, dir_path: &str) -> String {
+        let key = self.object_key(dir_path);
+
+        if key.is_empty() || key.ends_with('/') {
+            key
+        } else {
+            format!("{}/", key)
+        }
+    }
+}
+
+impl RemoteBackend for S3Backend {
+    async fn check_connection(self: &Self) -> bool {
+        list_objects(self, &self.prefix, true, true).await.is_ok()
+    }
+
+    async fn list(self: &Self, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+        list_remote_tree(self, dir_path, recursive).await
+    }
+
+    async fn stat(self: &Self, path: &str) -> Result<Option<RemoteEntry>> {
+        get_remote_entry(self, path).await
+    }
+
+    // Listings give the upload time, the metadata time needs HEAD
+    fn lists_modified_times(self: &Self) -> bool {
+        false
+    }
+
+    async fn read(self: &Self, path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead> {
+        download_object(self, path, offset, etag).await
+    }
+
+    async fn write(self: &Self, path: &str, source: &UploadSource) -> Result<()> {
+        upload_object_resumable(self, path, source).await
+    }
+
+    async fn mkdir(self: &Self, dir_path: &str) -> Result<()> {
+        make_directory_marker(self, dir_path).await
+    }
+
+    async fn delete(self: &Self, path: &str) -> Result<()> {
+        // An empty key would address the bucket itself
+        if path.trim_matches('/').is_empty() {
+            return Err(anyhow!("Can't delete the root of the bucket"));
+        }
+
+        // The path may be a file or the marker of a directory, deleting a missing key succeeds
+        delete_object(self, &self.object_key(path)).await?;
+        delete_object(self, &self.dir_prefix(path)).await
+    }
+
+    async fn rename(self: &Self, from_path: &str, to_path: &str) -> Result<()> {
+        copy_object(self, &self.object_key(from_path), &self.object_key(to_path), None).await?;
+        delete_object(self, &self.object_key(from_path)).await
+    }
+
+    async fn set_modified(self: &Self, path: &str, modified: DateTime<Utc>) -> Result<bool> {
+        set_object_modified(self, path, modified).await?;
+        Ok(true)
+    }
+
+    async fn checksum(self: &Self, path: &str) -> Result<Option<String>> {
+        let Some(headers) = head_object(self, &self.object_key(path)).await? else {
+            return Ok(None);
+        };
+
+        Ok(header_value(&headers, CHECKSUM_HEADER).filter(|checksum| checksum.starts_with(&format!("{}:", HASH_ALGORITHM))))
+    }
+
+    async fn tag(self: &Self, path: &str, _known_tag: Option<&str>) -> Result<Option<String>> {
+        get_remote_tag(self, path).await
+    }
+}
+
+
+// LISTING
+async fn list_remote_tree(backend: &S3Backend, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+    let dir_prefix = backend.dir_prefix(dir_path);
+    let (objects, common_prefixes) = list_objects(backend, &dir_prefix, recursive, false).await?;
+
+    // Directories exist only as long as keys below them do, the root of the backend always exists
+    if objects.is_empty() && common_prefixes.is_empty() && dir_prefix != backend.prefix {
+        return Ok(None);
+    }
+
+    let base_path = format!("/{}", dir_path.trim_matches('/'));
+    let base_path = base_path.trim_end_matches('/');
+    let mut dirs = BTreeSet::new();
+    let mut entries = Vec::new();
+
+    for (key, remote_file) in objects {
+        let Some(relative_key) = key.strip_prefix(&dir_prefix).filter(|relative_key| !relative_key.is_empty()) else {
+            continue;
+        };
+        let parts: Vec<&str> = relative_key.trim_end_matches('/').split('/').collect();
+
+        // Parents of deeper keys are directories even without markers
+        for depth in 1..parts.len() {
+            dirs.insert(parts[..depth].join("/"));
+        }
+
+        if relative_key.ends_with('/') {
+            dirs.insert(parts.join("/"));
+        } else {
+            entries.push((format!("{}/{}", base_path, relative_key), RemoteEntry::File(remote_file)));
+        }
+    }
+
+    for common_prefix in common_prefixes {
+        if let Some(relative_key) = common_prefix.strip_prefix(&dir_prefix) {
+            dirs.insert(relative_key.trim_end_matches('/').to_owned());
+        }
+    }
+
+    entries.extend(dirs.into_iter().map(|dir| (format!("{}/{}", base_path, dir), RemoteEntry::Directory)));
+    Ok(Some(entries))
+}
+
+// Objects with their full keys and, without recursion, the prefixes of subdirectories
+async fn list_objects(backend: &S3Backend, prefix: &str, recursive: bool, first_page: bool) -> Result<(Vec<(String, RemoteFile)>, Vec<String>)> {
+    let mut objects = Vec::new();
+    let mut common_prefixes = Vec::new();
+    let mut continuation_token: Option<String> = None;
+
+    loop {
+        let mut query = vec![("list-type", String::from("2")), ("prefix", prefix.to_owned())];
+        if !recursive {
+            query.push(("delimiter", String::from("/")));
+        }
+        if first_page {
+            query.push(("max-keys", String::from("1")));
+        }
+        if let Some(token) = &continuation_token {
+            query.push(("continuation-token", token.clone()));
+        }
+
+        let response = with_retry(backend.retry, || async {
+            check_transient_status(signed_request(backend, Method::GET, "", &query, &[])?.send().await?)
+        }).await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("List {} request unsuccess. Code: {}", prefix, response.status()));
+        }
+
+        let list_response = response.text().await?;
+
+        for contents in find_elements(&list_response, "Contents") {
+            let Some(key) = find_element(contents, "Key") else {
+                continue;
+            };
+            // The upload time, not the one from the metadata
+            let modified = find_element(contents, "LastModified")
+                .and_then(|modified| DateTime::parse_from_rfc3339(&modified).ok())
+                .map(|modified| modified.to_utc())
+                .unwrap_or_default();
+
+            objects.push((key, RemoteFile {
+                size: find_element(contents, "Size").and_then(|size| size.parse().ok()).unwrap_or_default(),
+                modified: modified,
+                etag: find_element(contents, "ETag")
+            }));
+        }
+
+        for common_prefix in find_elements(&list_response, "CommonPrefixes") {
+            if let Some(common_prefix) = find_element(common_prefix, "Prefix") {
+                common_prefixes.push(common_prefix);
+            }
+        }
+
+        continuation_token = find_element(&list_response, "NextContinuationToken");
+        if first_page || find_element(&list_response, "IsTruncated").as_deref() != Some("true") || continuation_token.is_none() {
+            break;
+        }
+    }
+
+    Ok((objects, common_prefixes))
+}
+
+async fn get_remote_entry(backend: &S3Backend, remote_path: &str) -> Result<Option<RemoteEntry>> {
+    if remote_path.trim_matches('/').is_empty() {
+        return Ok(Some(RemoteEntry::Directory));
+    }
+
+    if let Some(headers) = head_object(backend, &backend.object_key(remote_path)).await? {
+        return Ok(Some(RemoteEntry::File(to_remote_file(&headers))));
+    }
+
+    let (objects, common_prefixes) = list_objects(backend, &backend.dir_prefix(remote_path), false, true).await?;
+
+    if objects.is_empty() && common_prefixes.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(RemoteEntry::Directory))
+    }
+}
+
+
+// PROPERTIES
+async fn head_object(backend: &S3Backend, key: &str) -> Result<Option<HeaderMap>> {
+    let response = with_retry(backend.retry, || async {
+        check_transient_status(signed_request(backend, Method::HEAD, key, &[], &[])?.send().await?)
+    }).await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Head {} request unsuccess. Code: {}", key, response.status()));
+    }
+
+    Ok(Some(response.headers().clone()))
+}
+
+fn to_remote_file(headers: &HeaderMap) -> RemoteFile {
+    let modified = header_value(headers, MTIME_HEADER)
+        .and_then(|mtime| parse_mtime(&mtime))
+        .or_else(|| header_value(headers, LAST_MODIFIED.as_str())
+            .and_then(|modified| DateTime::parse_from_rfc2822(&modified).ok())
+            .map(|modified| modified.to_utc()))
+        .unwrap_or_default();
+
+    RemoteFile {
+        // HEAD responses have no body, so the length comes from the header itself
+        size: header_value(headers, CONTENT_LENGTH.as_str()).and_then(|size| size.parse().ok()).unwrap_or_default(),
+        modified: modified,
+        etag: header_value(headers, ETAG.as_str())
+    }
+}
+
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_owned)
+}
+
+// Seconds with a fraction, the same as rclone writes, so times of files uploaded by it are kept
+fn format_mtime(modified: DateTime<Utc>) -> String {
+    format!("{}.{:09}", modified.timestamp(), modified.timestamp_subsec_nanos())
+}
+
+fn parse_mtime(mtime: &str) -> Option<DateTime<Utc>> {
+    let (seconds, fraction) = mtime.split_once('.').unwrap_or((mtime, ""));
+    let nanos = format!("{:0<9}", fraction.chars().take(9).collect::<String>());
+
+    DateTime::from_timestamp(seconds.parse().ok()?, nanos.parse().ok()?)
+}
+
+// Objects have no tags of their directories, so everything below the path is hashed
+async fn get_remote_tag(backend: &S3Backend, remote_path: &str) -> Result<Option<String>> {
+    if !remote_path.trim_matches('/').is_empty()
+        && let Some(headers) = head_object(backend, &backend.object_key(remote_path)).await? {
+        return Ok(header_value(&headers, ETAG.as_str()));
+    }
+
+    let dir_prefix = backend.dir_prefix(remote_path);
+    let (objects, _) = list_objects(backend, &dir_prefix, true, false).await?;
+
+    if objects.is_empty() && dir_prefix != backend.prefix {
+        return Ok(Some(String::new()));
+    }
+
+    let mut hasher = Sha256::new();
+    for (key, remote_file) in objects {
+        hasher.update(key.as_bytes());
+        hasher.update(remote_file.etag.unwrap_or_default().as_bytes());
+    }
+
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
+
+fn find_elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let start_tag = format!("<{}>", name);
+    let end_tag = format!("</{}>", name);
+
+    xml.split(&start_tag)
+        .skip(1)
+        .filter_map(|element| element.split(&end_tag).next())
+        .collect()
+}
+
+fn find_element(xml: &str, name: &str) -> Option<String> {
+    let value = find_elements(xml, name).into_iter().next()?;
+    Some(xml_unescape(value.trim()))
+}
+
+fn xml_unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity.strip_prefix("#x").map(|code| u32::from_str_radix(code, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32)
+        };
+
+        match character {
+            Some(character) => {
+                unescaped.push(character);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    unescaped.push_str(rest);
+    unescaped
+}
+
+
+// CHANGING REMOTE FILES
+async fn download_object(backend: &S3Backend, remote_path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead> {
+    let key = backend.object_key(remote_path);
+
+    let mut response = match etag {
+        Some(etag) if offset > 0 => {
+            signed_request(backend, Method::GET, &key, &[], &[])?
+                .header(RANGE, format!("bytes={}-", offset))
+                .header(IF_MATCH, etag)
+                .send()
+                .await?
+        }
+        _ => signed_request(backend, Method::GET, &key, &[], &[])?.send().await?
+    };
+
+    // S3 has no If-Range, a changed object fails the precondition and is sent from the start
+    if response.status() == StatusCode::PRECONDITION_FAILED {
+        response = signed_request(backend, Method::GET, &key, &[], &[])?.send().await?;
+    }
+    let response = check_transient_status(response)?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Download {} request unsuccess. Code: {}", remote_path, response.status()));
+    }
+
+    let offset = if response.status() == StatusCode::PARTIAL_CONTENT { offset } else { 0 };
+
+    Ok(RemoteRead {
+        offset: offset,
+        length: response.content_length(),
+        stream: response.bytes_stream().map_err(anyhow::Error::from).boxed()
+    })
+}
+
+async fn delete_object(backend: &S3Backend, key: &str) -> Result<()> {
+    let response = with_retry(backend.retry, || async {
+        check_transient_status(signed_request(backend, Method::DELETE, key, &[], &[])?.send().await?)
+    }).await?;
+
+    if !response.status().is_success() && response.status() != 404 {
+        return Err(anyhow!("Delete {} request unsuccess. Code: {}", key, response.status()));
+    }
+
+    Ok(())
+}
+
+// Without new metadata the object keeps its own, otherwise it is replaced
+async fn copy_object(backend: &S3Backend, from_key: &str, to_key: &str, metadata: Option<Vec<(&str, String)>>) -> Result<()> {
+    let mut headers = vec![("x-amz-copy-source", format!("/{}/{}", backend.bucket, utf8_percent_encode(from_key, KEY_ENCODE_SET)))];
+    match metadata {
+        Some(metadata) => {
+            headers.push(("x-amz-metadata-directive", String::from("REPLACE")));
+            headers.extend(metadata);
+        }
+        None => headers.push(("x-amz-metadata-directive", String::from("COPY")))
+    }
+
+    let response = with_retry(backend.retry, || async {
+        check_transient_status(signed_request(backend, Method::PUT, to_key, &[], &headers)?.send().await?)
+    }).await?;
+    let status = response.status();
+
+    // Copying may fail after the server already answered 200, then the error is in the body
+    if !status.is_success() || response.text().await?.contains("<Error>") {
+        return Err(anyhow!("Copy {} request unsuccess. Code: {}", from_key, status));
+    }
+
+    Ok(())
+}
+
+async fn set_object_modified(backend: &S3Backend, remote_path: &str, modified: DateTime<Utc>) -> Result<()> {
+    let key = backend.object_key(remote_path);
+    let Some(headers) = head_object(backend, &key).await? else {
+        return Err(anyhow!("No object {}", remote_path));
+    };
+
+    let mut metadata = vec![(MTIME_HEADER, format_mtime(modified))];
+    if let Some(checksum) = header_value(&headers, CHECKSUM_HEADER) {
+        metadata.push((CHECKSUM_HEADER, checksum));
+    }
+
+    copy_object(backend, &key, &key, Some(metadata)).await
+}
+
+// An empty object ending with '/' keeps an empty directory listed, parents need none
+async fn make_directory_marker(backend: &S3Backend, dir_path: &str) -> Result<()> {
+    if dir_path.trim_matches('/').is_empty() {
+        return Ok(());
+    }
+
+    let key = backend.dir_prefix(dir_path);
+    let response = with_retry(backend.retry, || async {
+        let response = signed_request(backend, Method::PUT, &key, &[], &[])?
+            .header(CONTENT_LENGTH, 0)
+            .send()
+            .await?;
+        check_transient_status(response)
+    }).await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Make directory {} request unsuccess. Code: {}", dir_path, response.status()));
+    }
+
+    Ok(())
+}
+
+fn object_metadata(source: &UploadSource) -> Vec<(&'static str, String)> {
+    let mut metadata = vec![(MTIME_HEADER, format_mtime(source.modified))];
+    if let Some(checksum) = &source.checksum {
+        metadata.push((CHECKSUM_HEADER, checksum.clone()));
+    }
+    metadata
+}
+
+async fn upload_object(backend: &S3Backend, remote_path: &str, source: &UploadSource) -> Result<()> {
+    let key = backend.object_key(remote_path);
+    let metadata = object_metadata(source);
+
+    let response = with_retry(backend.retry, || async {
+        let response = signed_request(backend, Method::PUT, &key, &[], &metadata)?
+            .header(CONTENT_LENGTH, source.size)
+            .body(Body::wrap_stream(source.open(0, source.size).await?))
+            .send()
+            .await?;
+        check_transient_status(response)
+    }).await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Upload {} request unsuccess. Code: {}", remote_path, response.status()));
+    }
+
+    Ok(())
+}
+
+async fn upload_object_resumable(backend: &S3Backend, remote_path: &str, source: &UploadSource) -> Result<()> {
+    match source.local_path() {
+        Some(local_path) if source.size > PART_SIZE => upload_object_in_parts(backend, local_path, remote_path, source).await,
+        _ => upload_object(backend, remote_path, source).await
+    }
+}
+
+async fn upload_object_in_parts(backend: &S3Backend, local_path: &str, remote_path: &str, source: &UploadSource) -> Result<()> {
+    let key = backend.object_key(remote_path);
+    let size = source.size;
+
+    let mut partial_upload = match load_partial_upload::<MultipartUpload>(local_path).await? {
+        Some(partial_upload) if partial_upload.is_for(remote_path, source)
+            && is_multipart_upload_alive(backend, &key, &partial_upload).await? => {
+            partial_upload
+        }
+        stale_upload => {
+            if let Some(stale_upload) = stale_upload {
+                let _ = abort_multipart_upload(backend, &backend.object_key(&stale_upload.remote_path), &stale_upload).await;
+            }
+
+            // The metadata is given when the upload starts and ends up on the assembled object
+            let metadata = object_metadata(source);
+            let response = with_retry(backend.retry, || async {
+                check_transient_status(signed_request(backend, Method::POST, &key, &[("uploads", String::new())], &metadata)?.send().await?)
+            }).await?;
+
+            if !response.status().is_success() {
+                return Err(anyhow!("Start upload {} request unsuccess. Code: {}", remote_path, response.status()));
+            }
+
+            let Some(upload_id) = find_element(&response.text().await?, "UploadId") else {
+                return Err(anyhow!("No upload id for {}", remote_path));
+            };
+
+            PartialUpload::new(remote_path, source, MultipartUpload {
+                upload_id: upload_id,
+                // Parts are limited to ten thousand, very large files get larger parts
+                part_size: PART_SIZE.max(size.div_ceil(MAX_PARTS)),
+                part_etags: Vec::new()
+            })
+        }
+    };
+    save_partial_upload(local_path, &partial_upload).await?;
+
+    let parts = size.div_ceil(partial_upload.transfer.part_size);
+
+    while (partial_upload.transfer.part_etags.len() as u64) < parts {
+        let part_number = partial_upload.transfer.part_etags.len() as u64 + 1;
+        let offset = (part_number - 1) * partial_upload.transfer.part_size;
+        let length = partial_upload.transfer.part_size.min(size - offset);
+
+        let query = [("partNumber", part_number.to_string()), ("uploadId", partial_upload.transfer.upload_id.clone())];
+        let response = with_retry(backend.retry, || async {
+            let response = signed_request(backend, Method::PUT, &key, &query, &[])?
+                .header(CONTENT_LENGTH, length)
+                .body(Body::wrap_stream(source.open(offset, length).await?))
+                .send()
+                .await?;
+            check_transient_status(response)
+        }).await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Upload part {} of {} request unsuccess. Code: {}", part_number, remote_path, response.status()));
+        }
+
+        let Some(etag) = header_value(response.headers(), ETAG.as_str()) else {
+            return Err(anyhow!("No ETag for part {} of {}", part_number, remote_path));
+        };
+
+        partial_upload.transfer.part_etags.push(etag);
+        save_partial_upload(local_path, &partial_upload).await?;
+    }
+
+    let parts_xml: String = partial_upload.transfer.part_etags
+        .iter()
+        .enumerate()
+        .map(|(index, etag)| format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", index + 1, etag))
+        .collect();
+    let complete_xml = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts_xml);
+
+    let query = [("uploadId", partial_upload.transfer.upload_id.clone())];
+    let response = with_retry(backend.retry, || async {
+        check_transient_status(signed_request(backend, Method::POST, &key, &query, &[])?.body(complete_xml.clone()).send().await?)
+    }).await?;
+    let status = response.status();
+
+    // Completion may fail after the server already answered 200, then the error is in the body
+    if !status.is_success() || response.text().await?.contains("<Error>") {
+        // The parts don't make up the file, the next attempt starts a new upload
+        let _ = abort_multipart_upload(backend, &key, &partial_upload).await;
+        clear_partial_upload(local_path).await?;
+        return Err(anyhow!("Upload {} request unsuccess. Code: {}", remote_path, status));
+    }
+
+    clear_partial_upload(local_path).await?;
+    Ok(())
+}
+
+async fn is_multipart_upload_alive(backend: &S3Backend, key: &str, partial_upload: &PartialUpload<MultipartUpload>) -> Result<bool> {
+    let query = [("max-parts", String::from("1")), ("uploadId", partial_upload.transfer.upload_id.clone())];
+    let response = with_retry(backend.retry, || async {
+        check_transient_status(signed_request(backend, Method::GET, key, &query, &[])?.send().await?)
+    }).await?;
+
+    Ok(response.status().is_success())
+}
+
+// Parts of an abandoned upload take space in the bucket until it is aborted
+async fn abort_multipart_upload(backend: &S3Backend, key: &str, partial_upload: &PartialUpload<MultipartUpload>) -> Result<()> {
+    let query = [("uploadId", partial_upload.transfer.upload_id.clone())];
+    signed_request(backend, Method::DELETE, key, &query, &[])?.send().await?;
+    Ok(())
+}
+
+
+// SIGNING
+// AWS Signature Version 4. Bodies are streamed, so they are sent as UNSIGNED-PAYLOAD
+fn signed_request(backend: &S3Backend, method: Method, key: &str, query: &[(&str, String)], headers: &[(&str, String)]) -> Result<RequestBuilder> {
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+
+    let path = if key.is_empty() {
+        format!("/{}", backend.bucket)
+    } else {
+        format!("/{}/{}", backend.bucket, utf8_percent_encode(key, KEY_ENCODE_SET))
+    };
+
+    let mut query: Vec<String> = query
+        .iter()
+        .map(|(name, value)| format!("{}={}", utf8_percent_encode(name, URI_ENCODE_SET), utf8_percent_encode(value, URI_ENCODE_SET)))
+        .collect();
+    query.sort();
+    let query = query.join("&");
+
+    let mut signed_headers: Vec<(String, String)> = headers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_owned()))
+        .collect();
+    signed_headers.push((String::from("host"), backend.authority.clone()));
+    signed_headers.push((String::from("x-amz-content-sha256"), String::from(UNSIGNED_PAYLOAD)));
+    signed_headers.push((String::from("x-amz-date"), amz_date.clone()));
+    signed_headers.sort();
+
+    let canonical_headers: String = signed_headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+    let header_names = signed_headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";");
+    let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, header_names, UNSIGNED_PAYLOAD);
+
+    let scope = format!("{}/{}/s3/aws4_request", date, backend.region);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{:x}", amz_date, scope, Sha256::digest(canonical_request.as_bytes()));
+
+    let mut signing_key = hmac_sha256(format!("AWS4{}", backend.secret_key).as_bytes(), date.as_bytes())?;
+    for part in [backend.region.as_str(), "s3", "aws4_request"] {
+        signing_key = hmac_sha256(&signing_key, part.as_bytes())?;
+    }
+    let signature: String = hmac_sha256(&signing_key, string_to_sign.as_bytes())?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    let url = if query.is_empty() {
+        format!("{}{}", backend.endpoint, path)
+    } else {
+        format!("{}{}?{}", backend.endpoint, path, query)
+    };
+
+    let mut request = backend.client
+        .request(method, url)
+        .header(AUTHORIZATION, format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", backend.access_key, scope, header_names, signature));
+
+    // The client adds Host by itself
+    for (name, value) in signed_headers {
+        if name != "host" {
+            request = request.header(name, value);
+        }
+    }
+
+    Ok(request)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
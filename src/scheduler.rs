@@ -0,0 +1,71 @@
+use std::{sync::Arc, time::Duration};
+
+use iced::futures::{SinkExt, channel::mpsc};
+
+use filesync_rust::{SyncEngine, SyncPair, SyncPurpose};
+
+use crate::Message;
+
+#[derive(Debug, Clone, Hash)]
+pub struct Schedule {
+    pub sync_interval: Option<Duration>,
+    pub poll_interval: Option<Duration>,
+    pub purpose: SyncPurpose
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            sync_interval: None,
+            poll_interval: None,
+            purpose: SyncPurpose::Check
+        }
+    }
+}
+
+impl Schedule {
+    pub fn is_enabled(self: &Self) -> bool {
+        self.sync_interval.is_some() || self.poll_interval.is_some()
+    }
+}
+
+pub async fn run_schedule(output: mpsc::Sender<Message>, engine: SyncEngine, pairs: Arc<Vec<SyncPair>>, schedule: Schedule) {
+    let mut output = output;
+    let Some(tick) = [schedule.sync_interval, schedule.poll_interval].into_iter().flatten().min() else {
+        return;
+    };
+    let mut since_full_sync = Duration::ZERO;
+    let mut polling_failed = false;
+
+    loop {
+        tokio::time::sleep(tick).await;
+        since_full_sync += tick;
+
+        if schedule.sync_interval.is_some_and(|sync_interval| since_full_sync >= sync_interval) {
+            since_full_sync = Duration::ZERO;
+            let _ = output.send(Message::ScheduledSync(None)).await;
+            continue;
+        }
+
+        if schedule.poll_interval.is_none() {
+            continue;
+        }
+
+        match engine.poll_changed_pairs(&pairs).await {
+            Ok(changed_pairs) => {
+                polling_failed = false;
+
+                if !changed_pairs.is_empty() {
+                    let _ = output.send(Message::ScheduledSync(Some(changed_pairs))).await;
+                }
+            }
+            Err(e) => {
+                // The server may stay unreachable for hours, one message is enough
+                if !polling_failed {
+                    let _ = output.send(Message::ShowError(format!("Can't poll for changes: {}", e))).await;
+                }
+                polling_failed = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::{io::{Read, Seek, SeekFrom, Write}, net::TcpStream, path::{Path, PathBuf}, sync::Arc};
+
+use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
+use ssh2::{CheckResult, ErrorCode, FileStat, HashType, KnownHostFileKind, RenameFlags, Session, Sftp};
+use bytes::Bytes;
+use tokio::sync::{OnceCell, mpsc};
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, stream};
+use anyhow::{Result, anyhow};
+
+use crate::backend::{RemoteBackend, RemoteEntry, RemoteFile, RemoteRead, TreeNode, UploadSource, tree_fingerprint, upload_temp_path, walk_directory};
+
+const SFTP_SCHEME: &str = "sftp://";
+const DEFAULT_PORT: u16 = 22;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const SESSION_TIMEOUT_MS: u32 = 60_000;
+// LIBSSH2_FX_NO_SUCH_FILE
+const NO_SUCH_FILE: i32 = 2;
+
+#[derive(Debug, Clone)]
+struct SftpConfig {
+    host: String,
+    port: u16,
+    login: String,
+    password: String,
+    private_key: String,
+    host_key: String
+}
+
+// libssh2 is blocking, so every call goes to the blocking thread pool of tokio
+pub struct SftpBackend {
+    config: SftpConfig,
+    root: String,
+    sftp: OnceCell<Arc<Sftp>>
+}
+
+impl SftpBackend {
+    // Hosts like sftp://server:2222/srv/files pick this backend. With a private key the password unlocks the key
+    pub fn from_host(host: &str, login: &str, password: &str, private_key: &str, host_key: &str) -> Option<Self> {
+        let address = host.strip_prefix(SFTP_SCHEME)?;
+        let (authority, root) = match address.find('/') {
+            Some(index) => (&address[..index], &address[index..]),
+            None => (address, "")
+        };
+        let (hostname, port) = match authority.rsplit_once(':') {
+            Some((hostname, port)) => (hostname, port.parse().ok()?),
+            None => (authority, DEFAULT_PORT)
+        };
+
+        // Without a path in the address files are kept in the home directory of the user
+        let root = if root.is_empty() { "." } else { root.trim_end_matches('/') };
+
+        Some(SftpBackend {
+            config: SftpConfig {
+                host: hostname.to_owned(),
+                port: port,
+                login: login.to_owned(),
+                password: password.to_owned(),
+                private_key: private_key.to_owned(),
+                host_key: host_key.to_owned()
+            },
+            root: root.to_owned(),
+            sftp: OnceCell::new()
+        })
+    }
+
+    fn full_path(self: &Self, path: &str) -> PathBuf {
+        PathBuf::from(format!("{}/{}", self.root, path.trim_matches('/')))
+    }
+
+    // The session is opened on the first request and shared by all of them
+    async fn sftp(self: &Self) -> Result<Arc<Sftp>> {
+        let sftp = self.sftp.get_or_try_init(|| async {
+            let config = self.config.clone();
+            let sftp = tokio::task::spawn_blocking(move || connect(&config)).await??;
+            Ok::<_, anyhow::Error>(Arc::new(sftp))
+        }).await?;
+
+        Ok(sftp.clone())
+    }
+
+    async fn blocking<T, F>(self: &Self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&Sftp) -> Result<T> + Send + 'static,
+        T: Send + 'static
+    {
+        let sftp = self.sftp().await?;
+        tokio::task::spawn_blocking(move || operation(&sftp)).await?
+    }
+}
+
+impl RemoteBackend for SftpBackend {
+    async fn check_connection(self: &Self) -> bool {
+        let root = PathBuf::from(&self.root);
+        self.blocking(move |sftp| Ok(sftp.stat(&root)?.is_dir())).await.unwrap_or(false)
+    }
+
+    async fn list(self: &Self, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+        let dir_path = format!("/{}", dir_path.trim_matches('/'));
+        let root = self.root.clone();
+        self.blocking(move |sftp| list_directory(sftp, &root, &dir_path, recursive)).await
+    }
+
+    async fn stat(self: &Self, path: &str) -> Result<Option<RemoteEntry>> {
+        let full_path = self.full_path(path);
+        self.blocking(move |sftp| match sftp.stat(&full_path) {
+            Ok(stat) => Ok(Some(to_remote_entry(&stat))),
+            Err(e) if is_missing(&e) => Ok(None),
+            Err(e) => Err(e.into())
+        }).await
+    }
+
+    async fn read(self: &Self, path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead> {
+        read_file(self, path, offset, etag).await
+    }
+
+    async fn write(self: &Self, path: &str, source: &UploadSource) -> Result<()> {
+        write_file(self, path, source).await
+    }
+
+    async fn mkdir(self: &Self, dir_path: &str) -> Result<()> {
+        let full_path = self.full_path(dir_path);
+        self.blocking(move |sftp| make_directories(sftp, &full_path)).await
+    }
+
+    async fn delete(self: &Self, path: &str) -> Result<()> {
+        let full_path = self.full_path(path);
+        self.blocking(move |sftp| {
+            let stat = match sftp.stat(&full_path) {
+                Ok(stat) => stat,
+                Err(e) if is_missing(&e) => return Ok(()),
+                Err(e) => return Err(e.into())
+            };
+
+            if stat.is_dir() {
+                sftp.rmdir(&full_path)?;
+            } else {
+                sftp.unlink(&full_path)?;
+            }
+            Ok(())
+        }).await
+    }
+
+    async fn rename(self: &Self, from_path: &str, to_path: &str) -> Result<()> {
+        let from_path = self.full_path(from_path);
+        let to_path = self.full_path(to_path);
+        self.blocking(move |sftp| {
+            if let Some(parent) = to_path.parent() {
+                make_directories(sftp, parent)?;
+            }
+            replace_file(sftp, &from_path, &to_path)
+        }).await
+    }
+
+    async fn set_modified(self: &Self, path: &str, modified: DateTime<Utc>) -> Result<bool> {
+        let full_path = self.full_path(path);
+        self.blocking(move |sftp| {
+            sftp.setstat(&full_path, modified_stat(modified))?;
+            Ok(true)
+        }).await
+    }
+
+    async fn tag(self: &Self, path: &str, _known_tag: Option<&str>) -> Result<Option<String>> {
+        let full_path = self.full_path(path);
+        self.blocking(move |sftp| get_tree_fingerprint(sftp, &full_path)).await
+    }
+}
+
+fn connect(config: &SftpConfig) -> Result<Sftp> {
+    let tcp = TcpStream::connect((config.host.as_str(), config.port))?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(SESSION_TIMEOUT_MS);
+    session.handshake()?;
+
+    check_host_key(&session, config)?;
+
+    if config.private_key.is_empty() {
+        session.userauth_password(&config.login, &config.password)?;
+    } else {
+        let passphrase = (!config.password.is_empty()).then_some(config.password.as_str());
+        session.userauth_pubkey_file(&config.login, None, Path::new(&config.private_key), passphrase)?;
+    }
+
+    Ok(session.sftp()?)
+}
+
+// A server is trusted by its key in known_hosts or by the fingerprint saved in the profile
+fn check_host_key(session: &Session, config: &SftpConfig) -> Result<()> {
+    let Some((host_key, _)) = session.host_key() else {
+        return Err(anyhow!("Server {} sent no host key", config.host));
+    };
+    let Some(host_key_hash) = session.host_key_hash(HashType::Sha256) else {
+        return Err(anyhow!("Can't hash the host key of {}", config.host));
+    };
+    let fingerprint = format!("SHA256:{}", STANDARD_NO_PAD.encode(host_key_hash));
+
+    if !config.host_key.is_empty() && config.host_key != fingerprint {
+        return Err(anyhow!("Host key of {} is {}, not the one saved in the profile", config.host, fingerprint));
+    }
+
+    let mut known_hosts = session.known_hosts()?;
+    // Without HOME or known_hosts every server is unknown
+    if let Some(home) = std::env::var_os("HOME") {
+        let known_hosts_path = Path::new(&home).join(".ssh/known_hosts");
+        if known_hosts_path.exists() {
+            known_hosts.read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)
+                .map_err(|e| anyhow!("Can't read {}: {}", known_hosts_path.display(), e))?;
+        }
+    }
+
+    match known_hosts.check_port(&config.host, config.port, host_key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound if !config.host_key.is_empty() => Ok(()),
+        CheckResult::NotFound => Err(anyhow!("Server {} is unknown, its key is {}. Add it to known_hosts or save the fingerprint in the profile", config.host, fingerprint)),
+        CheckResult::Mismatch => Err(anyhow!("Host key of {} doesn't match the one in known_hosts", config.host)),
+        CheckResult::Failure => Err(anyhow!("Can't check the host key of {}", config.host))
+    }
+}
+
+fn list_directory(sftp: &Sftp, root: &str, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+    walk_directory(dir_path, recursive, |dir_path| {
+        let full_path = format!("{}/{}", root, dir_path.trim_matches('/'));
+
+        let listing = match sftp.readdir(Path::new(&full_path)) {
+            Ok(listing) => listing,
+            Err(e) if is_missing(&e) => return Ok(None),
+            Err(e) => return Err(e.into())
+        };
+
+        Ok(Some(listing
+            .iter()
+            .filter_map(|(path, stat)| Some((path.file_name()?.to_str()?.to_owned(), to_remote_entry(stat))))
+            .collect()))
+    })
+}
+
+async fn read_file(backend: &SftpBackend, path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead> {
+    let full_path = backend.full_path(path);
+    let etag = etag.map(str::to_owned);
+
+    let (mut file, size, offset) = backend.blocking(move |sftp| {
+        let mut file = sftp.open(&full_path)?;
+        let stat = file.stat()?;
+        let size = stat.size.unwrap_or_default();
+
+        let offset = if offset > 0 && etag.is_some() && etag == file_etag(&stat) { offset.min(size) } else { 0 };
+        file.seek(SeekFrom::Start(offset))?;
+        Ok((file, size, offset))
+    }).await?;
+
+    // The reading thread stops when the download is dropped and the receiver goes away
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut buffer = vec![0; READ_CHUNK_SIZE];
+
+        loop {
+            let chunk = match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => Ok(Bytes::copy_from_slice(&buffer[..read])),
+                Err(e) => Err(anyhow::Error::from(e))
+            };
+            let failed = chunk.is_err();
+
+            if sender.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    Ok(RemoteRead {
+        offset: offset,
+        length: Some(size - offset),
+        stream: stream.boxed()
+    })
+}
+
+async fn write_file(backend: &SftpBackend, path: &str, source: &UploadSource) -> Result<()> {
+    let full_path = backend.full_path(path);
+    let temp_path = upload_temp_path(&full_path);
+    let size = source.size;
+    let modified = source.modified;
+    let (sender, mut receiver) = mpsc::channel::<Bytes>(4);
+
+    let writer = backend.blocking(move |sftp| {
+        if let Some(parent) = full_path.parent() {
+            make_directories(sftp, parent)?;
+        }
+
+        let mut written = 0;
+        {
+            let mut file = sftp.create(&temp_path)?;
+            while let Some(chunk) = receiver.blocking_recv() {
+                file.write_all(&chunk)?;
+                written += chunk.len() as u64;
+            }
+        }
+
+        // The content stops short when reading the local file fails, such a file is not put in place
+        if written != size {
+            let _ = sftp.unlink(&temp_path);
+            return Err(anyhow!("Uploaded {} bytes instead of {} into {}", written, size, temp_path.display()));
+        }
+
+        sftp.setstat(&temp_path, modified_stat(modified))?;
+        replace_file(sftp, &temp_path, &full_path)
+    });
+
+    let feed = async move {
+        let mut stream = source.open(0, size).await?;
+
+        while let Some(chunk) = stream.next().await {
+            // The writer has failed and reports why
+            if sender.send(chunk?).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let (written, fed) = tokio::join!(writer, feed);
+    fed?;
+    written
+}
+
+fn make_directories(sftp: &Sftp, dir_path: &Path) -> Result<()> {
+    let mut current_path = PathBuf::new();
+
+    for component in dir_path.components() {
+        current_path.push(component);
+
+        if sftp.stat(&current_path).is_err() {
+            sftp.mkdir(&current_path, 0o755)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Servers on version 3 of the protocol refuse to rename over an existing file
+fn replace_file(sftp: &Sftp, from_path: &Path, to_path: &Path) -> Result<()> {
+    if sftp.rename(from_path, to_path, Some(RenameFlags::OVERWRITE)).is_ok() {
+        return Ok(());
+    }
+
+    match sftp.unlink(to_path) {
+        Err(e) if !is_missing(&e) => return Err(e.into()),
+        _ => {}
+    }
+    sftp.rename(from_path, to_path, None)?;
+    Ok(())
+}
+
+fn get_tree_fingerprint(sftp: &Sftp, full_path: &Path) -> Result<Option<String>> {
+    let stat = match sftp.stat(full_path) {
+        Ok(stat) => stat,
+        Err(e) if is_missing(&e) => return Ok(Some(String::new())),
+        Err(e) => return Err(e.into())
+    };
+
+    if !stat.is_dir() {
+        return Ok(file_etag(&stat));
+    }
+
+    let fingerprint = tree_fingerprint(vec![to_tree_node(full_path.to_path_buf(), &stat)], |dir_path| {
+        Ok(sftp.readdir(dir_path)?.into_iter().map(|(path, stat)| to_tree_node(path, &stat)).collect())
+    })?;
+    Ok(Some(fingerprint))
+}
+
+fn to_tree_node(path: PathBuf, stat: &FileStat) -> TreeNode {
+    TreeNode {
+        path: path,
+        size: stat.size.unwrap_or_default(),
+        modified: stat.mtime.unwrap_or_default() as u128,
+        is_dir: stat.is_dir()
+    }
+}
+
+fn to_remote_entry(stat: &FileStat) -> RemoteEntry {
+    if stat.is_dir() {
+        return RemoteEntry::Directory;
+    }
+
+    RemoteEntry::File(RemoteFile {
+        size: stat.size.unwrap_or_default(),
+        modified: DateTime::from_timestamp(stat.mtime.unwrap_or_default() as i64, 0).unwrap_or_default(),
+        etag: file_etag(stat)
+    })
+}
+
+// Size and modification time stand in for an ETag, SFTP has nothing better
+fn file_etag(stat: &FileStat) -> Option<String> {
+    Some(format!("{:x}-{:x}", stat.mtime?, stat.size?))
+}
+
+fn modified_stat(modified: DateTime<Utc>) -> FileStat {
+    let timestamp = modified.timestamp().max(0) as u64;
+
+    FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: None,
+        atime: Some(timestamp),
+        mtime: Some(timestamp)
+    }
+}
+
+fn is_missing(e: &ssh2::Error) -> bool {
+    matches!(e.code(), ErrorCode::SFTP(NO_SUCH_FILE))
+}
@@ -0,0 +1,95 @@
+use std::{collections::HashSet, path::Path, sync::Arc, time::Duration};
+
+use iced::futures::{SinkExt, channel::mpsc};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use tokio::time::Instant;
+
+use filesync_rust::{SyncPair, engine};
+
+use crate::Message;
+
+// Changes are collected until the pairs stay quiet for a while
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
+// A file that never stops changing still gets synchronized from time to time
+const DEBOUNCE_LIMIT: Duration = Duration::from_secs(30);
+
+pub async fn watch_pairs(output: mpsc::Sender<Message>, pairs: Arc<Vec<SyncPair>>) {
+    let mut output = output;
+    let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+
+    let mut watcher = match notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let _ = sender.send(event);
+    }) {
+        Ok(watcher) => { watcher }
+        Err(e) => {
+            let _ = output.send(Message::ShowError(format!("Can't watch local changes: {}", e))).await;
+            return;
+        }
+    };
+
+    for pair in pairs.iter() {
+        let local_path = &pair.local_path;
+        let path = Path::new(local_path);
+
+        // Editors often replace a file on save, so single files are watched through their directory
+        let watched = if path.is_dir() {
+            watcher.watch(path, RecursiveMode::Recursive)
+        } else {
+            match path.parent() {
+                Some(parent) => { watcher.watch(parent, RecursiveMode::NonRecursive) }
+                None => { continue; }
+            }
+        };
+
+        if let Err(e) = watched {
+            let _ = output.send(Message::ShowError(format!("Can't watch {}: {}", local_path, e))).await;
+        }
+    }
+
+    while let Some(event) = events.recv().await {
+        let mut changed_pairs = HashSet::new();
+        collect_changed_pairs(event, &pairs, &mut changed_pairs, &mut output).await;
+
+        let deadline = Instant::now() + DEBOUNCE_LIMIT;
+        loop {
+            let delay = DEBOUNCE_DELAY.min(deadline.saturating_duration_since(Instant::now()));
+
+            match tokio::time::timeout(delay, events.recv()).await {
+                Ok(Some(event)) => { collect_changed_pairs(event, &pairs, &mut changed_pairs, &mut output).await; }
+                Ok(None) => { return; }
+                Err(_) => { break; }
+            }
+        }
+
+        if !changed_pairs.is_empty() {
+            let _ = output.send(Message::LocalChanges(changed_pairs.into_iter().collect())).await;
+        }
+    }
+}
+
+async fn collect_changed_pairs(event: notify::Result<Event>, pairs: &[SyncPair], changed_pairs: &mut HashSet<String>, output: &mut mpsc::Sender<Message>) {
+    let event = match event {
+        Ok(event) => { event }
+        Err(e) => {
+            let _ = output.send(Message::ShowError(format!("Local changes watcher failed: {}", e))).await;
+            return;
+        }
+    };
+
+    if !matches!(event.kind, EventKind::Any | EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+        return;
+    }
+
+    for path in event.paths {
+        // Unfinished downloads are not local changes
+        if path.file_name().is_some_and(|name| engine::is_partial_download(&name.to_string_lossy())) {
+            continue;
+        }
+
+        for pair in pairs {
+            if path.starts_with(&pair.local_path) {
+                changed_pairs.insert(pair.local_path.clone());
+            }
+        }
+    }
+}
@@ -1,219 +1,457 @@
-use std::{cmp::Ordering, collections::HashMap, fs::Metadata, path::Path, sync::Arc};
+use std::{path::Path, sync::atomic::{AtomicBool, Ordering}};
 
-use reqwest_dav::{Auth, Client, ClientBuilder, Depth, list_cmd::{ListEntity, ListFile}};
-use tokio::{fs::{self, File}, io::AsyncWriteExt};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use reqwest::{Body, Method, Response, StatusCode, header::{CONTENT_LENGTH, CONTENT_TYPE, IF_RANGE, RANGE}};
+use reqwest_dav::{Auth, Client, ClientBuilder, Depth, list_cmd::ListEntity};
+use sha2::{Digest, Sha256};
 use chrono::{DateTime, Utc};
-use iced::futures::{SinkExt, channel::mpsc};
+use futures::{StreamExt, TryStreamExt};
 use anyhow::{Result, anyhow};
 
-use crate::{SyncState, Message};
+use crate::{backend::{HASH_ALGORITHM, PartialUpload, RemoteBackend, RemoteEntry, RemoteFile, RemoteRead, UploadSource, clear_partial_upload, load_partial_upload, save_partial_upload}, retry::{RetryPolicy, check_transient_status, with_retry}};
 
-const METADATA_FILENAME: &str = ".syncmetadata";
+const CHECKSUMS_PROPFIND: &str = r#"<?xml version="1.0"?><d:propfind xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns"><d:prop><oc:checksums/></d:prop></d:propfind>"#;
+const TAGS_PROPFIND: &str = r#"<?xml version="1.0"?><d:propfind xmlns:d="DAV:" xmlns:cs="http://calendarserver.org/ns/"><d:prop><d:resourcetype/><d:getetag/><cs:getctag/><d:sync-token/></d:prop></d:propfind>"#;
+const DAV_NAMESPACE: &str = "DAV:";
+const OWNCLOUD_NAMESPACE: &str = "http://owncloud.org/ns";
+const CALENDARSERVER_NAMESPACE: &str = "http://calendarserver.org/ns/";
+const UPLOAD_CHUNK_SIZE: u64 = 10 * 1024 * 1024;
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
 
-#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
-struct SyncMetadata {
-    files: HashMap<String, DateTime<Utc>>
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+struct ChunkedUpload {
+    transfer_id: String,
+    uploaded_chunks: u64
 }
 
-struct WebDavWorker {
+pub struct WebDavBackend {
     client: Client,
-    output: mpsc::Sender<Message>,
-    syncmetadata: Option<SyncMetadata>,
-    purpose: SyncPurpose
+    chunk_client: Option<Client>,
+    host: String,
+    host_path: String,
+    retry: RetryPolicy,
+    infinite_depth: AtomicBool
 }
 
-#[derive(Hash, Debug, Clone)]
-pub enum SyncPurpose {
-    Synchronize,
-    Check
+impl WebDavBackend {
+    pub fn new(host: String, login: String, password: String, max_attempts: u32) -> Result<Self> {
+        let host_path = percent_decode(url_path(&host)).trim_end_matches('/').to_owned();
+        let chunk_client = chunked_upload_host(&host).and_then(|uploads_host| ClientBuilder::new()
+            .set_host(uploads_host)
+            .set_auth(Auth::Basic(login.clone(), password.clone()))
+            .build()
+            .ok());
+        let client = ClientBuilder::new()
+            .set_host(host.clone())
+            .set_auth(Auth::Basic(login, password))
+            .build()?;
+
+        Ok(WebDavBackend {
+            client: client,
+            chunk_client: chunk_client,
+            host: host,
+            host_path: host_path,
+            retry: RetryPolicy::new(max_attempts),
+            infinite_depth: AtomicBool::new(true)
+        })
+    }
 }
 
-pub async fn run_sync(output: mpsc::Sender<Message>, host: String, login: String, password: String, pairs: Arc<Vec<(String, String)>>, purpose: SyncPurpose) {
-    let mut output = output;
+impl RemoteBackend for WebDavBackend {
+    async fn check_connection(self: &Self) -> bool {
+        with_retry(self.retry, || async { Ok(self.client.list("/", Depth::Number(0)).await?) }).await.is_ok()
+    }
 
-    let client = match ClientBuilder::new()
-        .set_host(host)
-        .set_auth(Auth::Basic(login, password))
-        .build() {
-            Ok(client) => { client }
-            Err(..) => {
-                let _ = output.send(Message::ShowError(String::from("Can't build client"))).await;
-                let _ = output.send(Message::StopSynchronize).await;
-                return;
-            }
-    };
+    async fn list(self: &Self, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+        list_remote_tree(self, dir_path, recursive).await
+    }
 
-    if !check_connection(&client).await {
-        let _ = output.send(Message::ShowError(String::from("Can't open connection"))).await;
-        let _ = output.send(Message::StopSynchronize).await;
-        return;
+    async fn stat(self: &Self, path: &str) -> Result<Option<RemoteEntry>> {
+        get_remote_entry(self, path).await
     }
 
-    let syncmetadata = load_metadata(&client).await.ok();
-    let mut worker = WebDavWorker {
-        client: client,
-        output: output,
-        syncmetadata: syncmetadata,
-        purpose: purpose
-    };
+    async fn read(self: &Self, path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead> {
+        download_file(self, path, offset, etag).await
+    }
 
-    if let Err(e) = synchronize_files(&mut worker, &pairs).await {
-        let _ = worker.output.send(Message::ShowError(e.to_string())).await;
-        let _ = worker.output.send(Message::StopSynchronize).await;
-        return;
+    async fn write(self: &Self, path: &str, source: &UploadSource) -> Result<()> {
+        upload_file_resumable(self, path, source).await
     }
 
-    if let SyncPurpose::Synchronize = worker.purpose {
-        if let Err(e) = save_and_upload_metadata(&worker.client, &pairs, &mut worker.syncmetadata.take().unwrap_or_default()).await {
-            let _ = worker.output.send(Message::ShowError(e.to_string())).await;
-        }
+    async fn mkdir(self: &Self, dir_path: &str) -> Result<()> {
+        make_remote_directories(self, dir_path).await
     }
 
-    let _ = worker.output.send(Message::StopSynchronize).await;
+    async fn delete(self: &Self, path: &str) -> Result<()> {
+        delete_remote_file(self, path).await
+    }
+
+    async fn rename(self: &Self, from_path: &str, to_path: &str) -> Result<()> {
+        move_remote_file(self, from_path, to_path).await
+    }
+
+    async fn set_modified(self: &Self, path: &str, modified: DateTime<Utc>) -> Result<bool> {
+        set_remote_modified(self, path, modified).await
+    }
+
+    async fn checksum(self: &Self, path: &str) -> Result<Option<String>> {
+        get_remote_checksum(self, path).await
+    }
+
+    async fn tag(self: &Self, path: &str, known_tag: Option<&str>) -> Result<Option<String>> {
+        get_remote_tag(self, path, known_tag).await
+    }
 }
 
-async fn synchronize_files(worker: &mut WebDavWorker, pairs: &Vec<(String, String)>) -> Result<()> {
-    for (key, value) in pairs.iter() {
-        if let Err(e) = synchronize_file(worker, key, value).await {
-            worker.output.send(Message::ShowError(e.to_string())).await?;
+
+// LISTING
+async fn list_remote_tree(backend: &WebDavBackend, dir_path: &str, recursive: bool) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+    if recursive && backend.infinite_depth.load(Ordering::Relaxed) {
+        match list_remote_dir(backend, dir_path, Depth::Infinity).await {
+            Ok(entries) => return Ok(entries),
+            // Many servers refuse Depth: infinity, so the tree is walked level by level from now on
+            Err(_) => backend.infinite_depth.store(false, Ordering::Relaxed)
         }
     }
-    
-    Ok(())
-}
 
-async fn synchronize_file(worker: &mut WebDavWorker, local_path: &str, remote_path: &str) -> Result<()> {
-    if is_local_file_exist(local_path).await && is_remote_file_exist(&worker.client, remote_path).await? {
-        match compare_modified_time(worker, local_path, remote_path).await? {
-            Ordering::Greater => {
-                return sync_through_uploading(worker, local_path, remote_path).await;
-            },
-            Ordering::Less => {
-                return sync_through_downloading(worker, local_path, remote_path).await;
-            },
-            _ => {
-                worker.output.send(Message::UpdatePairSyncState(local_path.to_owned(), SyncState::Synchronized)).await?;
-                return Ok(());
+    let Some(mut entries) = list_remote_dir(backend, dir_path, Depth::Number(1)).await? else {
+        return Ok(None);
+    };
+
+    if recursive {
+        let mut pending_dirs: Vec<String> = entries
+            .iter()
+            .filter(|(_, entry)| matches!(entry, RemoteEntry::Directory))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        while let Some(dir_path) = pending_dirs.pop() {
+            for (path, entry) in list_remote_dir(backend, &dir_path, Depth::Number(1)).await?.unwrap_or_default() {
+                if matches!(entry, RemoteEntry::Directory) {
+                    pending_dirs.push(path.clone());
+                }
+                entries.push((path, entry));
             }
         }
-    } else if is_local_file_exist(local_path).await && !is_remote_file_exist(&worker.client, remote_path).await? {
-        return sync_through_uploading(worker, local_path, remote_path).await;
-    } else if !is_local_file_exist(local_path).await && is_remote_file_exist(&worker.client, remote_path).await? {
-        if is_download_possible(local_path).await {
-            return sync_through_downloading(worker, local_path, remote_path).await;
-        } else {
-            return send_sync_impossible(&mut worker.output, local_path, "Not all dirs in path exist").await;
-        }
     }
-    send_sync_impossible(&mut worker.output, local_path, "Local and remote files don't exist").await
+
+    Ok(Some(entries))
 }
 
-// SYNCHRONIZE WAYS
-async fn sync_through_downloading(worker: &mut WebDavWorker, local_path: &str, remote_path: &str) -> Result<()> {
-    match &worker.purpose {
-        SyncPurpose::Synchronize => {
-            download_file(&worker.client, local_path, remote_path).await?;
-            worker.output.send(Message::UpdatePairSyncState(local_path.to_owned(), SyncState::Synchronized)).await?;
+async fn list_remote_dir(backend: &WebDavBackend, dir_path: &str, depth: Depth) -> Result<Option<Vec<(String, RemoteEntry)>>> {
+    let dir_key = normalize_path(dir_path);
+    let request_path = format!("{}/", dir_key.trim_end_matches('/'));
+
+    let listvec = match with_retry(backend.retry, || async { Ok(backend.client.list(&request_path, depth.clone()).await?) }).await {
+        Ok(listvec) => listvec,
+        Err(e) => {
+            if !is_remote_missing(backend, &request_path).await? {
+                return Err(e);
+            }
+            return Ok(None);
         }
-        SyncPurpose::Check => {
-            worker.output.send(Message::UpdatePairSyncState(local_path.to_owned(), SyncState::UnsynchronizedLocal)).await?;
+    };
+
+    // The collection itself comes first in the answer
+    let entries = listvec
+        .into_iter()
+        .map(|entity| to_remote_entry(backend, entity))
+        .filter(|(path, _)| *path != dir_key)
+        .collect();
+
+    Ok(Some(entries))
+}
+
+async fn get_remote_entry(backend: &WebDavBackend, remote_path: &str) -> Result<Option<RemoteEntry>> {
+    match with_retry(backend.retry, || async { Ok(backend.client.list(remote_path, Depth::Number(0)).await?) }).await {
+        Ok(listvec) => Ok(listvec.into_iter().next().map(|entity| to_remote_entry(backend, entity).1)),
+        Err(e) => {
+            if !is_remote_missing(backend, remote_path).await? {
+                return Err(e);
+            }
+            Ok(None)
         }
     }
-    
-    return Ok(());
 }
 
-async fn sync_through_uploading(worker: &mut WebDavWorker, local_path: &str, remote_path: &str) -> Result<()> {
-    match &worker.purpose {
-        SyncPurpose::Synchronize => {
-            upload_file(&worker.client, local_path, remote_path).await?;
-            worker.output.send(Message::UpdatePairSyncState(local_path.to_owned(), SyncState::Synchronized)).await?;
+async fn is_remote_missing(backend: &WebDavBackend, remote_path: &str) -> Result<bool> {
+    let response = with_retry(backend.retry, || async {
+        check_transient_status(backend.client.list_raw(remote_path, Depth::Number(0)).await?)
+    }).await?;
+
+    Ok(response.status() == StatusCode::NOT_FOUND)
+}
+
+fn to_remote_entry(backend: &WebDavBackend, entity: ListEntity) -> (String, RemoteEntry) {
+    match entity {
+        ListEntity::File(listfile) => {
+            let remote_file = RemoteFile {
+                size: listfile.content_length as u64,
+                modified: listfile.last_modified,
+                etag: listfile.tag
+            };
+            (normalize_path(&remote_href_to_path(&backend.host_path, &listfile.href)), RemoteEntry::File(remote_file))
         }
-        SyncPurpose::Check => {
-            worker.output.send(Message::UpdatePairSyncState(local_path.to_owned(), SyncState::UnsynchronizedRemote)).await?;
+        ListEntity::Folder(listfolder) => {
+            (normalize_path(&remote_href_to_path(&backend.host_path, &listfolder.href)), RemoteEntry::Directory)
         }
     }
-    
-    return Ok(());
 }
 
-async fn send_sync_impossible(output: &mut mpsc::Sender<Message>, local_path: &str, msg: &str) -> Result<()> {
-    let _ = output.send(Message::UpdatePairSyncState(local_path.to_owned(), SyncState::CantSynchronize)).await;
-    Err(anyhow!("For file {}: {}", local_path, msg))
+fn normalize_path(remote_path: &str) -> String {
+    format!("/{}", remote_path.trim_matches('/'))
 }
 
+fn remote_href_to_path(host_path: &str, href: &str) -> String {
+    let path = percent_decode(url_path(href));
+    path.strip_prefix(host_path).unwrap_or(&path).to_owned()
+}
 
-// FUNCTIONS FOR SAVING REMOTE FILES METADATA
-async fn save_and_upload_metadata(
-    client: &Client,
-    pairs: &[(String, String)],
-    syncmetadata: &mut SyncMetadata
-) -> Result<()> {
-    for (local_path, remote_path) in pairs {
-        let file_metadata = get_local_file_info(local_path).await?;
-        let modified = file_metadata.modified()?;
-        let datetime: DateTime<Utc> = modified.into();
-        syncmetadata.files.insert(remote_path.clone(), datetime);
+fn url_path(url: &str) -> &str {
+    match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("", |index| &rest[index..]),
+        None => url
     }
+}
 
-    let data = postcard::to_allocvec(&syncmetadata)?;
-    let temp_path = std::env::temp_dir().join(METADATA_FILENAME);
-    let mut file = File::create(&temp_path).await?; 
-    file.write_all(&data).await?;
+fn percent_decode(path: &str) -> String {
+    percent_encoding::percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
 
-    let temp_filepath = match temp_path.to_str() {
-        Some(path) => { path }
-        None => { return Err(anyhow!("Can't get temp file path")) }
-    };
+fn remote_url(host: &str, remote_path: &str) -> String {
+    format!("{}/{}", host.trim_end_matches('/'), utf8_percent_encode(remote_path.trim_start_matches('/'), PATH_ENCODE_SET))
+}
 
-    if let Err(e) =  upload_file(client, temp_filepath, METADATA_FILENAME).await {
-        fs::remove_file(&temp_path).await?;
-        return Err(e);
+
+// PROPERTIES
+async fn propfind(backend: &WebDavBackend, remote_path: &str, body: &'static str) -> Result<Response> {
+    with_retry(backend.retry, || async {
+        let response = backend.client
+            .start_request(Method::from_bytes(b"PROPFIND")?, remote_path)
+            .await?
+            .header("Depth", "0")
+            .header(CONTENT_TYPE, "application/xml")
+            .body(body)
+            .send()
+            .await?;
+        check_transient_status(response)
+    }).await
+}
+
+async fn get_remote_checksum(backend: &WebDavBackend, remote_path: &str) -> Result<Option<String>> {
+    let response = propfind(backend, remote_path, CHECKSUMS_PROPFIND).await?;
+
+    if !response.status().is_success() {
+        return Ok(None);
     }
-    
-    fs::remove_file(&temp_path).await?;
-    Ok(())
+
+    find_checksum(&response.text().await?)
 }
 
-async fn load_metadata(client: &Client) -> Result<SyncMetadata> {
-    let temp_path = std::env::temp_dir().join(METADATA_FILENAME);
+// oc:checksum lists checksums of several algorithms separated by spaces, like "SHA1:... MD5:... SHA256:..."
+fn find_checksum(propfind_response: &str) -> Result<Option<String>> {
+    let document = roxmltree::Document::parse(propfind_response)?;
+    let prefix = format!("{}:", HASH_ALGORITHM);
 
-    let temp_filepath = match temp_path.to_str() {
-        Some(path) => { path }
-        None => { return Err(anyhow!("Can't get temp file path")) }
-    };
-    
-    download_file(client, temp_filepath, METADATA_FILENAME).await?;
+    let checksum = document
+        .descendants()
+        .filter(|node| node.has_tag_name((OWNCLOUD_NAMESPACE, "checksum")))
+        .filter_map(|node| node.text())
+        .flat_map(str::split_whitespace)
+        .find_map(|checksum| checksum.to_ascii_uppercase().starts_with(&prefix).then(|| checksum[prefix.len()..].to_ascii_lowercase()))
+        .filter(|checksum| !checksum.is_empty() && checksum.chars().all(|c| c.is_ascii_hexdigit()));
+
+    Ok(checksum.map(|checksum| format!("{}{}", prefix, checksum)))
+}
+
+// The ETag of a collection doesn't have to change with its contents, only ctag and sync-token do
+async fn get_remote_tag(backend: &WebDavBackend, remote_path: &str, known_tag: Option<&str>) -> Result<Option<String>> {
+    let response = propfind(backend, remote_path, TAGS_PROPFIND).await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(Some(String::new()));
+    }
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Can't poll {}. Code: {}", remote_path, response.status()));
+    }
+
+    let propfind_response = response.text().await?;
+    let document = roxmltree::Document::parse(&propfind_response)?;
+
+    if !document.descendants().any(|node| node.has_tag_name((DAV_NAMESPACE, "collection"))) {
+        return Ok(find_property(&document, DAV_NAMESPACE, "getetag"));
+    }
+
+    if let Some(ctag) = find_property(&document, CALENDARSERVER_NAMESPACE, "getctag") {
+        return Ok(Some(ctag));
+    }
+
+    // The sync-token moves with any change of the collection, the report tells if a file below really changed since the known one
+    match (find_property(&document, DAV_NAMESPACE, "sync-token"), known_tag) {
+        (Some(sync_token), Some(known_tag)) if sync_token != known_tag && !has_changes_since(backend, remote_path, known_tag).await? => {
+            Ok(Some(known_tag.to_owned()))
+        }
+        (sync_token, _) => { Ok(sync_token) }
+    }
+}
+
+// RFC 6578 sync-collection report, it lists only members changed or removed since the token
+async fn has_changes_since(backend: &WebDavBackend, remote_path: &str, sync_token: &str) -> Result<bool> {
+    let body = format!(
+        r#"<?xml version="1.0"?><d:sync-collection xmlns:d="DAV:"><d:sync-token>{}</d:sync-token><d:sync-level>infinite</d:sync-level><d:prop><d:getetag/></d:prop></d:sync-collection>"#,
+        escape_xml(sync_token)
+    );
+    let response = with_retry(backend.retry, || async {
+        let response = backend.client
+            .start_request(Method::from_bytes(b"REPORT")?, remote_path)
+            .await?
+            .header("Depth", "0")
+            .header(CONTENT_TYPE, "application/xml")
+            .body(body.clone())
+            .send()
+            .await?;
+        check_transient_status(response)
+    }).await?;
+
+    // An expired token or a server without the report can't tell what changed
+    if response.status() != StatusCode::MULTI_STATUS {
+        return Ok(true);
+    }
+
+    let multistatus = response.text().await?;
+    let document = roxmltree::Document::parse(&multistatus)?;
+    Ok(document.descendants().any(|node| node.has_tag_name((DAV_NAMESPACE, "response"))))
+}
 
-    let data = fs::read(&temp_path)
-        .await
-        .and_then(|data| { let _ = std::fs::remove_file(&temp_path); Ok(data) })?;
+fn find_property(document: &roxmltree::Document, namespace: &str, name: &str) -> Option<String> {
+    document
+        .descendants()
+        .find(|node| node.has_tag_name((namespace, name)))
+        .and_then(|node| node.text())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_owned)
+}
 
-    Ok(postcard::from_bytes::<SyncMetadata>(&data)?)
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
 }
 
 
-// DOWNLOAD AND UPLOAD FILES
-async fn download_file(client: &Client, local_path: &str, remote_path: &str) -> Result<()> {
-    let response = client.get(remote_path).await?;
+// CHANGING REMOTE FILES
+async fn download_file(backend: &WebDavBackend, remote_path: &str, offset: u64, etag: Option<&str>) -> Result<RemoteRead> {
+    let response = match etag {
+        Some(etag) if offset > 0 => {
+            backend.client
+                .start_request(Method::GET, remote_path)
+                .await?
+                .header(RANGE, format!("bytes={}-", offset))
+                .header(IF_RANGE, etag)
+                .send()
+                .await?
+        }
+        _ => backend.client.get(remote_path).await?
+    };
+    let response = check_transient_status(response)?;
 
-    if response.status().is_success() {
-        let bytes = response.bytes().await?;
-        let mut file = File::create(local_path).await?;
-        file.write_all(&bytes).await?;
-    } else {
+    if !response.status().is_success() {
         return Err(anyhow!("Download {} request unsuccess. Code: {}", remote_path, response.status()));
     }
-    
+
+    // The server answers 200 instead of 206 when the file changed or ranges are unsupported
+    let offset = if response.status() == StatusCode::PARTIAL_CONTENT { offset } else { 0 };
+
+    Ok(RemoteRead {
+        offset: offset,
+        length: response.content_length(),
+        stream: response.bytes_stream().map_err(anyhow::Error::from).boxed()
+    })
+}
+
+async fn delete_remote_file(backend: &WebDavBackend, remote_path: &str) -> Result<()> {
+    let response = with_retry(backend.retry, || async { check_transient_status(backend.client.delete_raw(remote_path).await?) }).await?;
+
+    if !response.status().is_success() && response.status() != 404 {
+        return Err(anyhow!("Delete {} request unsuccess. Code: {}", remote_path, response.status()));
+    }
+
+    Ok(())
+}
+
+async fn move_remote_file(backend: &WebDavBackend, from_path: &str, to_path: &str) -> Result<()> {
+    ensure_remote_directories(backend, to_path).await?;
+
+    let destination = remote_url(&backend.host, to_path);
+    let response = with_retry(backend.retry, || async {
+        let response = backend.client
+            .start_request(Method::from_bytes(b"MOVE")?, from_path)
+            .await?
+            .header("Destination", &destination)
+            .header("Overwrite", "T")
+            .send()
+            .await?;
+        check_transient_status(response)
+    }).await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Move {} request unsuccess. Code: {}", from_path, response.status()));
+    }
+
     Ok(())
 }
 
-async fn ensure_remote_directories(client: &Client, remote_path: &str) -> Result<()> {
+// ownCloud and Nextcloud take lastmodified as Unix time, other servers refuse it and the time stays in .syncmetadata
+async fn set_remote_modified(backend: &WebDavBackend, remote_path: &str, modified: DateTime<Utc>) -> Result<bool> {
+    let body = format!(
+        r#"<?xml version="1.0"?><d:propertyupdate xmlns:d="DAV:"><d:set><d:prop><d:lastmodified>{}</d:lastmodified></d:prop></d:set></d:propertyupdate>"#,
+        modified.timestamp()
+    );
+    let response = with_retry(backend.retry, || async {
+        let response = backend.client
+            .start_request(Method::from_bytes(b"PROPPATCH")?, remote_path)
+            .await?
+            .header(CONTENT_TYPE, "application/xml")
+            .body(body.clone())
+            .send()
+            .await?;
+        check_transient_status(response)
+    }).await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Set modified {} request unsuccess. Code: {}", remote_path, response.status()));
+    }
+
+    if response.status() != StatusCode::MULTI_STATUS {
+        return Ok(true);
+    }
+
+    // Every property gets its own status inside the multistatus
+    let multistatus = response.text().await?;
+    let document = roxmltree::Document::parse(&multistatus)?;
+    let statuses: Vec<&str> = document
+        .descendants()
+        .filter(|node| node.has_tag_name((DAV_NAMESPACE, "status")))
+        .filter_map(|node| node.text())
+        .collect();
+
+    Ok(!statuses.is_empty() && statuses.into_iter().all(is_success_status))
+}
+
+fn is_success_status(status_line: &str) -> bool {
+    status_line.split_whitespace().nth(1).is_some_and(|code| code.starts_with('2'))
+}
+
+async fn ensure_remote_directories(backend: &WebDavBackend, remote_path: &str) -> Result<()> {
     let dir_path = Path::new(remote_path)
         .parent()
         .and_then(|p| p.to_str())
         .unwrap_or("");
 
+    make_remote_directories(backend, dir_path).await
+}
+
+async fn make_remote_directories(backend: &WebDavBackend, dir_path: &str) -> Result<()> {
     if dir_path.is_empty() || dir_path == "/" {
         return Ok(());
     }
@@ -228,8 +466,8 @@ async fn ensure_remote_directories(client: &Client, remote_path: &str) -> Result
 
         current_path.push_str(part);
         current_path.push('/');
-        
-        let response = client.mkcol_raw(&current_path).await?;
+
+        let response = with_retry(backend.retry, || async { check_transient_status(backend.client.mkcol_raw(&current_path).await?) }).await?;
 
         if response.status() != 405 && response.status() != 201 {
             return Err(anyhow!("Unexpected status while making new remote dirs {}", response.status()));
@@ -239,61 +477,184 @@ async fn ensure_remote_directories(client: &Client, remote_path: &str) -> Result
     Ok(())
 }
 
-async fn upload_file(client: &Client, local_path: &str, remote_path: &str) -> Result<()> {
-    ensure_remote_directories(client, remote_path).await?;
+async fn upload_file(backend: &WebDavBackend, remote_path: &str, source: &UploadSource) -> Result<()> {
+    ensure_remote_directories(backend, remote_path).await?;
 
-    let content = tokio::fs::read(local_path).await?;
-    client.put(remote_path, content).await?;
-    
-    Ok(())
-}
+    let response = with_retry(backend.retry, || async {
+        let mut request = backend.client
+            .start_request(Method::PUT, remote_path)
+            .await?
+            .header(CONTENT_LENGTH, source.size);
 
+        if let Some(checksum) = &source.checksum {
+            request = request.header("OC-Checksum", checksum);
+        }
 
-// OTHER USEFUL FUNCTIONS
-async fn check_connection(client: &Client) -> bool {
-    client.list("/", Depth::Number(0)).await.is_ok()
-}
+        let response = request
+            .body(Body::wrap_stream(source.open(0, source.size).await?))
+            .send()
+            .await?;
+        check_transient_status(response)
+    }).await?;
 
-async fn is_local_file_exist(filepath: &str) -> bool {
-    Path::new(filepath).exists()
+    if !response.status().is_success() {
+        return Err(anyhow!("Upload {} request unsuccess. Code: {}", remote_path, response.status()));
+    }
+
+    Ok(())
 }
 
-async fn is_remote_file_exist(client: &Client, filepath: &str) -> Result<bool> {
-    Ok(client.list_raw(filepath, Depth::Number(0)).await?.status() != 404)
+async fn upload_file_resumable(backend: &WebDavBackend, remote_path: &str, source: &UploadSource) -> Result<()> {
+    match (&backend.chunk_client, source.local_path()) {
+        (Some(chunk_client), Some(local_path)) if source.size > UPLOAD_CHUNK_SIZE => {
+            upload_file_in_chunks(backend, chunk_client, local_path, remote_path, source).await
+        }
+        _ => upload_file(backend, remote_path, source).await
+    }
 }
 
-async fn get_remote_file_info(client: &Client, filepath: &str) -> Result<ListFile> {
-    let listvec = client.list(filepath, Depth::Number(0)).await?;
+async fn upload_file_in_chunks(backend: &WebDavBackend, chunk_client: &Client, local_path: &str, remote_path: &str, source: &UploadSource) -> Result<()> {
+    ensure_remote_directories(backend, remote_path).await?;
+
+    let size = source.size;
+    let destination = remote_url(&backend.host, remote_path);
+
+    let mut partial_upload = match load_partial_upload::<ChunkedUpload>(local_path).await? {
+        Some(partial_upload) if partial_upload.is_for(remote_path, source)
+            && is_chunked_upload_alive(backend, chunk_client, &partial_upload).await? => {
+            partial_upload
+        }
+        stale_upload => {
+            if let Some(stale_upload) = stale_upload {
+                let _ = chunk_client.delete_raw(&format!("{}/", stale_upload.transfer.transfer_id)).await;
+            }
 
-    if let Some(ListEntity::File(listfile)) = listvec.first() {
-        Ok(listfile.clone())
-    } else {
-        Err(anyhow!("Remote file {} not found", filepath))
+            let transfer_id = new_transfer_id(local_path);
+            let response = with_retry(backend.retry, || async {
+                let response = chunk_client
+                    .start_request(Method::from_bytes(b"MKCOL")?, &format!("{}/", transfer_id))
+                    .await?
+                    .header("Destination", &destination)
+                    .send()
+                    .await?;
+                check_transient_status(response)
+            }).await?;
+
+            if response.status() != StatusCode::CREATED {
+                // No chunking support on this server, the file goes in a single request
+                return upload_file(backend, remote_path, source).await;
+            }
+
+            PartialUpload::new(remote_path, source, ChunkedUpload {
+                transfer_id: transfer_id,
+                uploaded_chunks: 0
+            })
+        }
+    };
+    save_partial_upload(local_path, &partial_upload).await?;
+
+    let chunks = size.div_ceil(UPLOAD_CHUNK_SIZE);
+
+    while partial_upload.transfer.uploaded_chunks < chunks {
+        let offset = partial_upload.transfer.uploaded_chunks * UPLOAD_CHUNK_SIZE;
+        let length = UPLOAD_CHUNK_SIZE.min(size - offset);
+
+        // Chunk names are numbers starting from 1, the server joins them in that order
+        let chunk_path = format!("{}/{}", partial_upload.transfer.transfer_id, partial_upload.transfer.uploaded_chunks + 1);
+        let response = with_retry(backend.retry, || async {
+            let response = chunk_client
+                .start_request(Method::PUT, &chunk_path)
+                .await?
+                .header("Destination", &destination)
+                .header("OC-Total-Length", size)
+                .header(CONTENT_LENGTH, length)
+                .body(Body::wrap_stream(source.open(offset, length).await?))
+                .send()
+                .await?;
+            check_transient_status(response)
+        }).await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Upload chunk {} request unsuccess. Code: {}", chunk_path, response.status()));
+        }
+
+        partial_upload.transfer.uploaded_chunks += 1;
+        save_partial_upload(local_path, &partial_upload).await?;
     }
+
+    let response = with_retry(backend.retry, || async {
+        let mut request = chunk_client
+            .start_request(Method::from_bytes(b"MOVE")?, &format!("{}/.file", partial_upload.transfer.transfer_id))
+            .await?
+            .header("Destination", &destination)
+            .header("OC-Total-Length", size);
+
+        if let Some(checksum) = &source.checksum {
+            request = request.header("OC-Checksum", checksum);
+        }
+
+        check_transient_status(request.send().await?)
+    }).await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Upload {} request unsuccess. Code: {}", remote_path, response.status()));
+    }
+
+    clear_partial_upload(local_path).await?;
+    Ok(())
 }
 
-async fn get_local_file_info(filepath: &str) -> Result<Metadata> {
-    Ok(fs::metadata(Path::new(filepath)).await?)
+async fn is_chunked_upload_alive(backend: &WebDavBackend, chunk_client: &Client, partial_upload: &PartialUpload<ChunkedUpload>) -> Result<bool> {
+    let upload_path = format!("{}/", partial_upload.transfer.transfer_id);
+    let response = with_retry(backend.retry, || async {
+        check_transient_status(chunk_client.list_raw(&upload_path, Depth::Number(0)).await?)
+    }).await?;
+
+    Ok(response.status().is_success())
 }
 
-async fn is_download_possible(local_path: &str) -> bool {
-    Path::new(local_path).parent().is_some_and(|path| {
-        path.exists()
-    })
+
+fn new_transfer_id(local_path: &str) -> String {
+    let seed = format!("{}{}", local_path, Utc::now().timestamp_nanos_opt().unwrap_or_default());
+    format!("filesync-{:x}", Sha256::digest(seed.as_bytes()))
 }
 
-async fn compare_modified_time(worker: &WebDavWorker, local_path: &str, remote_path: &str) -> Result<Ordering> {
-    let metadata = get_local_file_info(local_path).await?;
+fn chunked_upload_host(host: &str) -> Option<String> {
+    // Nextcloud serves chunked uploads for https://server/remote.php/dav/files/<user> under .../dav/uploads/<user>
+    let (server, files_path) = host.split_once("/remote.php/dav/files/")?;
+    let user = files_path.split('/').next().filter(|user| !user.is_empty())?;
 
-    if let Some(syncmetadata) = &worker.syncmetadata {
-        if let Some(datetime) = syncmetadata.files.get(remote_path) {
-            let metadata_dt: DateTime<Utc> = metadata.modified()?.into();
-            return Ok(metadata_dt.cmp(&datetime));
-        }
+    Some(format!("{}/remote.php/dav/uploads/{}", server, user))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_properties_under_any_prefix() {
+        let propfind_response = r#"<?xml version="1.0"?>
+            <D:multistatus xmlns:D="DAV:" xmlns:CS="http://calendarserver.org/ns/">
+                <D:response><D:propstat><D:prop>
+                    <D:resourcetype><D:collection/></D:resourcetype>
+                    <CS:getctag xml:lang="en"> ctag-1 </CS:getctag>
+                    <D:sync-token/>
+                </D:prop></D:propstat></D:response>
+            </D:multistatus>"#;
+        let document = roxmltree::Document::parse(propfind_response).unwrap();
+
+        assert!(document.descendants().any(|node| node.has_tag_name((DAV_NAMESPACE, "collection"))));
+        assert_eq!(find_property(&document, CALENDARSERVER_NAMESPACE, "getctag").as_deref(), Some("ctag-1"));
+        assert_eq!(find_property(&document, DAV_NAMESPACE, "sync-token"), None);
     }
-    
-    let listfile = get_remote_file_info(&worker.client, remote_path).await?;
-    let metadata_dt: DateTime<Utc> = metadata.modified()?.into();
 
-    return Ok(metadata_dt.cmp(&listfile.last_modified));
-}
\ No newline at end of file
+    #[test]
+    fn finds_sha256_among_checksums() {
+        let propfind_response = r#"<d:multistatus xmlns:d="DAV:"><d:response><d:propstat><d:prop>
+            <checksums xmlns="http://owncloud.org/ns"><checksum>SHA1:AB12 sha256:CD34 MD5:EF56</checksum></checksums>
+        </d:prop></d:propstat></d:response></d:multistatus>"#;
+
+        assert_eq!(find_checksum(propfind_response).unwrap().as_deref(), Some("SHA256:cd34"));
+    }
+}
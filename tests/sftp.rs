@@ -0,0 +1,113 @@
+// Runs against the OpenSSH container from docker-compose.sftp.yml, its key has to be in known_hosts
+// or in FILESYNC_SFTP_HOST_KEY: cargo test --test sftp -- --ignored
+use std::{collections::BTreeMap, env, fs, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt, channel::mpsc};
+
+use filesync_rust::{ConflictPolicy, RemoteBackend, ServerProfile, SyncEngine, SyncEvent, SyncPair, SyncPurpose, SyncSettings, SyncState, backend::{RemoteEntry, UploadSource}, db, sftp::SftpBackend};
+
+const HOST: &str = "sftp://localhost:2222/upload";
+const LOGIN: &str = "filesync";
+const PASSWORD: &str = "filesync-password";
+
+fn host_key() -> String {
+    env::var("FILESYNC_SFTP_HOST_KEY").unwrap_or_default()
+}
+
+fn connect() -> SftpBackend {
+    SftpBackend::from_host(HOST, LOGIN, PASSWORD, "", &host_key()).unwrap()
+}
+
+// Every test works in its own directory on the server
+fn unique_dir(name: &str) -> String {
+    format!("/{}-{}", name, Utc::now().timestamp_nanos_opt().unwrap_or_default())
+}
+
+async fn read_to_string(backend: &SftpBackend, path: &str) -> String {
+    let remote_read = backend.read(path, 0, None).await.unwrap();
+    let chunks: Vec<_> = remote_read.stream.try_collect().await.unwrap();
+    String::from_utf8(chunks.concat()).unwrap()
+}
+
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn backend_writes_renames_and_deletes_files() {
+    let backend = connect();
+    assert!(backend.check_connection().await);
+
+    let dir = unique_dir("backend");
+    let path = format!("{}/a.txt", dir);
+    let renamed_path = format!("{}/nested/b.txt", dir);
+
+    backend.write(&path, &UploadSource::from_bytes(b"hello".to_vec())).await.unwrap();
+    assert!(matches!(backend.stat(&path).await.unwrap(), Some(RemoteEntry::File(file)) if file.size == 5));
+    assert_eq!(read_to_string(&backend, &path).await, "hello");
+
+    backend.rename(&path, &renamed_path).await.unwrap();
+    assert!(backend.stat(&path).await.unwrap().is_none());
+    assert_eq!(read_to_string(&backend, &renamed_path).await, "hello");
+
+    let modified: DateTime<Utc> = "2020-01-02T03:04:05Z".parse().unwrap();
+    assert!(backend.set_modified(&renamed_path, modified).await.unwrap());
+    match backend.stat(&renamed_path).await.unwrap() {
+        Some(RemoteEntry::File(file)) => { assert_eq!(file.modified, modified); }
+        entry => { panic!("Unexpected entry {:?}", entry); }
+    }
+
+    let listed: Vec<String> = backend.list(&dir, true).await.unwrap().unwrap().into_iter().map(|(path, _)| path).collect();
+    assert!(listed.contains(&renamed_path), "{:?}", listed);
+
+    backend.delete(&renamed_path).await.unwrap();
+    backend.delete(&format!("{}/nested", dir)).await.unwrap();
+    backend.delete(&dir).await.unwrap();
+    assert!(backend.stat(&dir).await.unwrap().is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn synchronizes_a_pair() {
+    let db_dir = tempfile::tempdir().unwrap();
+    db::set_path(db_dir.path().join("filesyncrs.redb")).unwrap();
+
+    let local = tempfile::tempdir().unwrap();
+    fs::write(local.path().join("a.txt"), "first").unwrap();
+
+    let remote_dir = unique_dir("pair");
+    let profile = ServerProfile {
+        host: HOST.to_owned(),
+        login: LOGIN.to_owned(),
+        password: PASSWORD.to_owned(),
+        host_key: host_key(),
+        ..ServerProfile::default()
+    };
+    let engine = SyncEngine::new(BTreeMap::from([(String::from("sftp"), profile)]), SyncSettings::default());
+    let pairs = Arc::new(vec![SyncPair {
+        local_path: local.path().to_string_lossy().into_owned(),
+        remote_path: remote_dir.clone(),
+        profile: String::from("sftp")
+    }]);
+
+    for purpose in [SyncPurpose::Synchronize, SyncPurpose::Check] {
+        let (events, receiver) = mpsc::channel(100);
+        let (_, events) = tokio::join!(
+            engine.run(events, pairs.clone(), purpose, ConflictPolicy::Defer),
+            receiver.collect::<Vec<SyncEvent>>()
+        );
+
+        let errors: Vec<&String> = events.iter().filter_map(|event| match event { SyncEvent::Error(e) => Some(e), _ => None }).collect();
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(events.iter().any(|event| matches!(event, SyncEvent::PairState(_, SyncState::Synchronized))));
+    }
+
+    let backend = connect();
+    assert_eq!(read_to_string(&backend, &format!("{}/a.txt", remote_dir)).await, "first");
+
+    for (path, entry) in backend.list(&remote_dir, false).await.unwrap().unwrap_or_default() {
+        if matches!(entry, RemoteEntry::File(_)) {
+            backend.delete(&path).await.unwrap();
+        }
+    }
+    backend.delete(&remote_dir).await.unwrap();
+}
@@ -0,0 +1,357 @@
+use std::{collections::BTreeMap, fs::{self, File}, path::{Path, PathBuf}, sync::{Arc, OnceLock}, time::{Duration, SystemTime}};
+
+use futures::{StreamExt, channel::mpsc};
+use tempfile::TempDir;
+
+use filesync_rust::{Conflict, ConflictPolicy, ConflictResolution, ServerProfile, SyncEngine, SyncEvent, SyncPair, SyncPurpose, SyncSettings, SyncState, db, engine::{self, PairOptions}};
+
+const PROFILE: &str = "test";
+
+// One database per test binary, every test has its own directories and pair in it
+fn use_temp_database() {
+    static DIR: OnceLock<TempDir> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = tempfile::tempdir().unwrap();
+        db::set_path(dir.path().join("filesyncrs.redb")).unwrap();
+        dir
+    });
+}
+
+struct TestPair {
+    _dir: TempDir,
+    local: PathBuf,
+    remote: PathBuf,
+    profiles: BTreeMap<String, ServerProfile>,
+    engine: SyncEngine,
+    pairs: Arc<Vec<SyncPair>>
+}
+
+impl TestPair {
+    // The server is another directory reached through file://
+    fn new() -> Self {
+        use_temp_database();
+
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("local");
+        let server = dir.path().join("server");
+        let remote = server.join("files");
+        fs::create_dir_all(&local).unwrap();
+        fs::create_dir_all(&remote).unwrap();
+
+        let profile = ServerProfile {
+            host: format!("file://{}", server.display()),
+            ..ServerProfile::default()
+        };
+        let pair = SyncPair {
+            local_path: local.to_string_lossy().into_owned(),
+            remote_path: String::from("/files"),
+            profile: PROFILE.to_owned()
+        };
+
+        let profiles = BTreeMap::from([(PROFILE.to_owned(), profile)]);
+
+        TestPair {
+            _dir: dir,
+            local: local,
+            remote: remote,
+            engine: SyncEngine::new(profiles.clone(), SyncSettings::default()),
+            profiles: profiles,
+            pairs: Arc::new(vec![pair])
+        }
+    }
+
+    // Another pair next to this one, its local path starts with the local path of this one
+    fn sibling_pair(self: &Self, name: &str) -> (SyncPair, PathBuf, PathBuf) {
+        let local = self.local.with_file_name(format!("local{}", name));
+        let remote = self.remote.with_file_name(format!("files{}", name));
+        fs::create_dir_all(&local).unwrap();
+        fs::create_dir_all(&remote).unwrap();
+
+        let pair = SyncPair {
+            local_path: local.to_string_lossy().into_owned(),
+            remote_path: format!("/files{}", name),
+            profile: PROFILE.to_owned()
+        };
+        (pair, local, remote)
+    }
+
+    async fn run(self: &Self, purpose: SyncPurpose) -> RunReport {
+        self.run_with(purpose, ConflictPolicy::Defer).await
+    }
+
+    async fn run_with(self: &Self, purpose: SyncPurpose, conflict_policy: ConflictPolicy) -> RunReport {
+        let (events, receiver) = mpsc::channel(100);
+        let (_, events) = tokio::join!(
+            self.engine.run(events, self.pairs.clone(), purpose, conflict_policy),
+            receiver.collect::<Vec<SyncEvent>>()
+        );
+
+        let mut report = RunReport { state: None, conflicts: Vec::new(), errors: Vec::new() };
+        for event in events {
+            match event {
+                SyncEvent::PairState(_, syncstate) => { report.state = Some(syncstate); }
+                SyncEvent::Conflict(conflict) => { report.conflicts.push(conflict); }
+                SyncEvent::Error(e) => { report.errors.push(e); }
+                _ => {}
+            }
+        }
+
+        report
+    }
+}
+
+struct RunReport {
+    state: Option<SyncState>,
+    conflicts: Vec<Conflict>,
+    errors: Vec<String>
+}
+
+fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+fn read(path: &Path) -> String {
+    fs::read_to_string(path).unwrap()
+}
+
+// A file changed on both sides since the last synchronization
+async fn make_conflict(pair: &TestPair) -> Conflict {
+    write(&pair.local.join("a.txt"), "original");
+    pair.run(SyncPurpose::Synchronize).await;
+
+    write(&pair.local.join("a.txt"), "changed locally");
+    write(&pair.remote.join("a.txt"), "changed on the server");
+
+    let mut report = pair.run(SyncPurpose::Synchronize).await;
+    assert_eq!(report.conflicts.len(), 1);
+    report.conflicts.remove(0)
+}
+
+async fn resolve_conflict(pair: &TestPair, resolution: ConflictResolution) -> RunReport {
+    let conflict = make_conflict(pair).await;
+    pair.run_with(SyncPurpose::Synchronize, ConflictPolicy::Answered(vec![(conflict, resolution)])).await
+}
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_new_local_files() {
+    let pair = TestPair::new();
+    write(&pair.local.join("a.txt"), "first");
+    write(&pair.local.join("dir/b.txt"), "second");
+
+    let report = pair.run(SyncPurpose::Check).await;
+    assert_eq!(report.state, Some(SyncState::UnsynchronizedRemote));
+    assert!(!pair.remote.join("a.txt").exists());
+
+    let report = pair.run(SyncPurpose::Synchronize).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.state, Some(SyncState::Synchronized));
+    assert_eq!(read(&pair.remote.join("a.txt")), "first");
+    assert_eq!(read(&pair.remote.join("dir/b.txt")), "second");
+
+    assert_eq!(pair.run(SyncPurpose::Check).await.state, Some(SyncState::Synchronized));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn downloads_new_server_files() {
+    let pair = TestPair::new();
+    write(&pair.remote.join("a.txt"), "from server");
+    write(&pair.remote.join("dir/b.txt"), "nested");
+
+    let report = pair.run(SyncPurpose::Synchronize).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.state, Some(SyncState::Synchronized));
+    assert_eq!(read(&pair.local.join("a.txt")), "from server");
+    assert_eq!(read(&pair.local.join("dir/b.txt")), "nested");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn propagates_deletions() {
+    let pair = TestPair::new();
+    write(&pair.local.join("local.txt"), "deleted locally");
+    write(&pair.local.join("remote.txt"), "deleted on the server");
+    write(&pair.local.join("kept.txt"), "kept");
+    pair.run(SyncPurpose::Synchronize).await;
+
+    fs::remove_file(pair.local.join("local.txt")).unwrap();
+    fs::remove_file(pair.remote.join("remote.txt")).unwrap();
+
+    let report = pair.run(SyncPurpose::Synchronize).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.state, Some(SyncState::Synchronized));
+    assert!(!pair.remote.join("local.txt").exists());
+    assert!(!pair.local.join("remote.txt").exists());
+    assert_eq!(read(&pair.local.join("kept.txt")), "kept");
+    assert_eq!(read(&pair.remote.join("kept.txt")), "kept");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn defers_conflicts() {
+    let pair = TestPair::new();
+    write(&pair.local.join("a.txt"), "original");
+    pair.run(SyncPurpose::Synchronize).await;
+
+    write(&pair.local.join("a.txt"), "changed locally");
+    write(&pair.remote.join("a.txt"), "changed on the server");
+
+    let report = pair.run(SyncPurpose::Synchronize).await;
+    assert_eq!(report.state, Some(SyncState::Conflict));
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(report.conflicts[0].remote_path, "/files/a.txt");
+
+    // Nothing is overwritten until the conflict is resolved
+    assert_eq!(read(&pair.local.join("a.txt")), "changed locally");
+    assert_eq!(read(&pair.remote.join("a.txt")), "changed on the server");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_local_file_of_a_conflict() {
+    let pair = TestPair::new();
+
+    let report = resolve_conflict(&pair, ConflictResolution::KeepLocal).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.state, Some(SyncState::Synchronized));
+    assert_eq!(read(&pair.remote.join("a.txt")), "changed locally");
+    assert_eq!(read(&pair.local.join("a.txt")), "changed locally");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_remote_file_of_a_conflict() {
+    let pair = TestPair::new();
+
+    let report = resolve_conflict(&pair, ConflictResolution::KeepRemote).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.state, Some(SyncState::Synchronized));
+    assert_eq!(read(&pair.local.join("a.txt")), "changed on the server");
+    assert_eq!(read(&pair.remote.join("a.txt")), "changed on the server");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_both_files_of_a_conflict() {
+    let pair = TestPair::new();
+
+    let report = resolve_conflict(&pair, ConflictResolution::KeepBoth).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(read(&pair.local.join("a.txt")), "changed on the server");
+
+    // The local version stays next to it as a conflict copy
+    let copies: Vec<PathBuf> = fs::read_dir(&pair.local)
+        .unwrap()
+        .map(|dir_entry| dir_entry.unwrap().path())
+        .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with("a (conflict "))
+        .collect();
+    assert_eq!(copies.len(), 1);
+    assert_eq!(read(&copies[0]), "changed locally");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn leaves_a_conflict_decided_later() {
+    let pair = TestPair::new();
+
+    let report = resolve_conflict(&pair, ConflictResolution::DecideLater).await;
+    assert_eq!(report.state, Some(SyncState::Conflict));
+    assert_eq!(read(&pair.local.join("a.txt")), "changed locally");
+    assert_eq!(read(&pair.remote.join("a.txt")), "changed on the server");
+
+    // The conflict is reported again by the next run
+    assert_eq!(pair.run(SyncPurpose::Synchronize).await.conflicts.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stops_at_the_deletion_limit() {
+    let pair = TestPair::new();
+    write(&pair.local.join("a.txt"), "a");
+    write(&pair.local.join("b.txt"), "b");
+    write(&pair.local.join("c.txt"), "c");
+    pair.run(SyncPurpose::Synchronize).await;
+
+    let pair_options = PairOptions { max_deletions: Some(1), ..PairOptions::default() };
+    engine::save_pair_options(&pair.pairs[0].local_path, &pair_options).unwrap();
+    fs::remove_file(pair.local.join("a.txt")).unwrap();
+    fs::remove_file(pair.local.join("b.txt")).unwrap();
+
+    let report = pair.run(SyncPurpose::Synchronize).await;
+    assert_eq!(report.state, Some(SyncState::CantSynchronize));
+    assert!(report.errors.iter().any(|e| e.contains("exceed the limit")), "{:?}", report.errors);
+    assert_eq!(read(&pair.remote.join("a.txt")), "a");
+    assert_eq!(read(&pair.remote.join("b.txt")), "b");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn treats_a_touched_file_as_unchanged() {
+    let pair = TestPair::new();
+    write(&pair.local.join("a.txt"), "same content");
+    pair.run(SyncPurpose::Synchronize).await;
+    let remote_modified = fs::metadata(pair.remote.join("a.txt")).unwrap().modified().unwrap();
+
+    let touched = SystemTime::now() + Duration::from_secs(3600);
+    File::options().write(true).open(pair.local.join("a.txt")).unwrap().set_modified(touched).unwrap();
+
+    assert_eq!(pair.run(SyncPurpose::Check).await.state, Some(SyncState::Synchronized));
+    let report = pair.run(SyncPurpose::Synchronize).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.state, Some(SyncState::Synchronized));
+    assert_eq!(fs::metadata(pair.remote.join("a.txt")).unwrap().modified().unwrap(), remote_modified);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resumes_a_partial_download() {
+    let pair = TestPair::new();
+    let content: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    fs::write(pair.remote.join("a.bin"), &content).unwrap();
+
+    // A slow download is cancelled with the first progress report
+    let limited = SyncEngine::new(pair.profiles.clone(), SyncSettings { download_limit: Some(64), ..SyncSettings::default() });
+    let (events, mut receiver) = mpsc::channel(100);
+    let cancel_midway = async {
+        let mut sync_handle = None;
+        while let Some(event) = receiver.next().await {
+            match event {
+                SyncEvent::Started(handle) => { sync_handle = Some(handle); }
+                SyncEvent::TransferProgress(..) => { sync_handle.iter().for_each(|handle| handle.cancel.cancel()); }
+                _ => {}
+            }
+        }
+    };
+    tokio::join!(limited.run(events, pair.pairs.clone(), SyncPurpose::Synchronize, ConflictPolicy::Defer), cancel_midway);
+
+    let partial_path = pair.local.join(".a.bin.filesync-part");
+    let partial_size = fs::metadata(&partial_path).unwrap().len() as usize;
+    assert!(partial_size > 0 && partial_size < content.len(), "{}", partial_size);
+    assert!(!pair.local.join("a.bin").exists());
+
+    // Bytes already on disk are not downloaded again, so the marked ones stay
+    fs::write(&partial_path, vec![b'x'; partial_size]).unwrap();
+
+    let report = pair.run(SyncPurpose::Synchronize).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    let downloaded = fs::read(pair.local.join("a.bin")).unwrap();
+    assert_eq!(downloaded.len(), content.len());
+    assert!(downloaded[..partial_size].iter().all(|byte| *byte == b'x'));
+    assert_eq!(downloaded[partial_size..], content[partial_size..]);
+    assert!(!partial_path.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_states_of_pairs_sharing_a_prefix() {
+    let mut pair = TestPair::new();
+    let (sibling, sibling_local, sibling_remote) = pair.sibling_pair("2");
+    write(&pair.local.join("a.txt"), "a");
+    write(&sibling_local.join("b.txt"), "b");
+
+    pair.pairs = Arc::new(vec![pair.pairs[0].clone(), sibling.clone()]);
+    let report = pair.run(SyncPurpose::Synchronize).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(read(&sibling_remote.join("b.txt")), "b");
+
+    // Forgetting the first pair leaves the sibling synchronized, so its deletion still goes to the server
+    engine::forget_sync_state(&pair.pairs[0].local_path).unwrap();
+    fs::remove_file(sibling_local.join("b.txt")).unwrap();
+
+    pair.pairs = Arc::new(vec![sibling]);
+    let report = pair.run(SyncPurpose::Synchronize).await;
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert!(!sibling_remote.join("b.txt").exists());
+    assert!(!sibling_local.join("b.txt").exists());
+}